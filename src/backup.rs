@@ -0,0 +1,201 @@
+use crate::{disk, Error};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How many previous versions of a state file to keep.
+///
+/// Before the file is overwritten its contents are moved to `<file>.1`, with
+/// older backups shifted to `<file>.2` and so on.
+///
+/// ```rust
+/// use statefile::Backups;
+/// use std::time::Duration;
+///
+/// // keep up to 10 backups, none older than a week
+/// let backups = Backups::keep(10).max_age(Duration::from_secs(7 * 24 * 60 * 60));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backups {
+    count: u32,
+    max_age: Option<Duration>,
+}
+
+impl Backups {
+    /// Keep up to `count` backups.
+    pub fn keep(count: u32) -> Self {
+        Backups {
+            count,
+            max_age: None,
+        }
+    }
+
+    /// Also remove backups last modified longer ago than `max_age`.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Move the current contents of `path` into the newest backup.
+    pub(crate) fn rotate(&self, path: &Path) -> Result<(), Error> {
+        if self.count == 0 || !path.exists() || disk::is_empty(path)? {
+            return Ok(());
+        }
+
+        // shift existing backups along, dropping the oldest
+        for generation in (1..self.count).rev() {
+            let from = backup_path(path, generation);
+            if from.exists() {
+                let to = backup_path(path, generation + 1);
+                std::fs::rename(&from, &to).map_err(|e| Error::io(&from, "rename", e))?;
+            }
+        }
+
+        // the file itself is about to be replaced by a rename, so linking keeps
+        // the current contents intact until then
+        let newest = backup_path(path, 1);
+        let _ = std::fs::remove_file(&newest);
+        if std::fs::hard_link(path, &newest).is_err() {
+            std::fs::copy(path, &newest).map_err(|e| Error::io(&newest, "copy", e))?;
+        }
+
+        self.prune(path)
+    }
+
+    /// Remove backups beyond the retention limits.
+    pub(crate) fn prune(&self, path: &Path) -> Result<(), Error> {
+        for backup in list(path)? {
+            let expired = match self.max_age {
+                Some(max_age) => backup.age() > max_age,
+                None => false,
+            };
+            if backup.generation > self.count || expired {
+                std::fs::remove_file(&backup.path)
+                    .map_err(|e| Error::io(&backup.path, "remove", e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A previous version of a state file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    path: PathBuf,
+    generation: u32,
+    modified: SystemTime,
+}
+
+impl Backup {
+    /// The path of the backup file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The position of this backup, with 1 being the most recent.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// When the backed up state was written.
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+
+    fn age(&self) -> Duration {
+        self.modified.elapsed().unwrap_or_default()
+    }
+}
+
+fn backup_path(path: &Path, generation: u32) -> PathBuf {
+    disk::sibling(path, &format!(".{}", generation))
+}
+
+/// List the backups of `path`, newest first.
+pub(crate) fn list(path: &Path) -> Result<Vec<Backup>, Error> {
+    let parent = disk::parent(path);
+    let prefix = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => format!("{}.", name),
+        None => return Ok(Vec::new()),
+    };
+
+    let entries = std::fs::read_dir(parent).map_err(|e| Error::io(parent, "list", e))?;
+
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| Error::io(parent, "list", e))?;
+        let name = entry.file_name();
+        let generation = name
+            .to_str()
+            .and_then(|n| n.strip_prefix(&prefix))
+            .and_then(|g| g.parse::<u32>().ok());
+
+        if let Some(generation) = generation.filter(|&g| g > 0) {
+            let path = backup_path(path, generation);
+            let modified = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .map_err(|e| Error::io(&path, "stat", e))?;
+            backups.push(Backup {
+                path,
+                generation,
+                modified,
+            });
+        }
+    }
+
+    backups.sort_by_key(|b| b.generation);
+    Ok(backups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backups_rotate() {
+        let test_path = Path::new("test_backups_rotate.json");
+        let backups = Backups::keep(2);
+
+        for contents in ["1", "2", "3", "4"] {
+            backups.rotate(test_path).unwrap();
+            disk::write_atomic(
+                test_path,
+                contents.as_bytes(),
+                &crate::Permissions::default(),
+                disk::Fsync::default(),
+            )
+            .unwrap();
+        }
+
+        let list = list(test_path).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].generation(), 1);
+        assert_eq!(std::fs::read_to_string(list[0].path()).unwrap(), "3");
+        assert_eq!(list[1].generation(), 2);
+        assert_eq!(std::fs::read_to_string(list[1].path()).unwrap(), "2");
+
+        for backup in list {
+            let _ = std::fs::remove_file(backup.path());
+        }
+        let _ = std::fs::remove_file(test_path); // Clean up test file
+    }
+
+    #[test]
+    fn test_backups_prune_age() {
+        let test_path = Path::new("test_backups_prune_age.json");
+        let backups = Backups::keep(5).max_age(Duration::from_secs(60));
+
+        std::fs::write("test_backups_prune_age.json.1", "new").unwrap();
+        let old = std::fs::File::create("test_backups_prune_age.json.2").unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(120))
+            .unwrap();
+        drop(old);
+
+        backups.prune(test_path).unwrap();
+        let list = list(test_path).unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].generation(), 1);
+
+        let _ = std::fs::remove_file("test_backups_prune_age.json.1"); // Clean up test file
+    }
+}
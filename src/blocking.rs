@@ -0,0 +1,498 @@
+//! A synchronous state file, for programs that don't use async.
+//!
+//! [`File`] behaves like [`crate::File`], with the same options and on-disk
+//! behaviour, but its methods block the calling thread instead of returning
+//! futures. It's available without any of the async runtime features.
+//!
+//! ```rust
+//! use statefile::blocking::File;
+//!
+//! let state = File::<u32>::new("blocking.json").unwrap();
+//! *state.write() += 1;
+//! state.flush().unwrap();
+//! # drop(state);
+//! # std::fs::remove_file("blocking.json").unwrap();
+//! # std::fs::remove_file("blocking.json.lock").unwrap();
+//! ```
+
+use crate::codec::Codec;
+use crate::format::{Format, Json};
+use crate::guard::{Parts, Progress};
+use crate::validate::Validator;
+use crate::writer::Writer;
+use crate::{backup, state_directory, Backup, Error, FileBuilder, OnPanic, Recovered, Stats};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+#[cfg(feature = "encryption")]
+use crate::Key;
+
+/// Exclusive write access to a blocking state file, see
+/// [`crate::WriteGuard`].
+pub struct WriteGuard<'a, T: Serialize + DeserializeOwned + Default, F: Format = Json> {
+    guard: RwLockWriteGuard<'a, T>,
+    file: &'a File<T, F>,
+    progress: Progress,
+}
+
+impl<'a, T: Serialize + DeserializeOwned + Default, F: Format> WriteGuard<'a, T, F> {
+    /// Write the state to disk and release the lock, returning any error that
+    /// occurred.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let encoded = self.progress.commit(&self.file.parts(), &mut self.guard)?;
+        self.file.writer.save_blocking(encoded)
+    }
+
+    /// Release the lock without writing to disk.
+    ///
+    /// Any changes made through this guard remain in memory and will be
+    /// written by the next guard that modifies the state or is committed.
+    pub fn abandon(mut self) {
+        self.progress.abandon();
+    }
+}
+
+impl<'a, T: Serialize + DeserializeOwned + Default, F: Format> Drop for WriteGuard<'a, T, F> {
+    fn drop(&mut self) {
+        self.progress.release(&self.file.parts(), &mut self.guard);
+    }
+}
+
+impl<'a, T: Serialize + DeserializeOwned + Default, F: Format> std::ops::Deref
+    for WriteGuard<'a, T, F>
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'a, T: Serialize + DeserializeOwned + Default, F: Format> std::ops::DerefMut
+    for WriteGuard<'a, T, F>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.progress.change(&self.file.parts(), &self.guard);
+        &mut self.guard
+    }
+}
+
+/// A state file accessed without async, see [`crate::File`].
+///
+/// The state is protected by a [`std::sync::RwLock`]. A panic while holding a
+/// guard doesn't poison the file; the state is used as the panicking thread
+/// left it.
+pub struct File<T: Serialize + DeserializeOwned + Default, F: Format = Json> {
+    data: Arc<RwLock<T>>,
+    codec: Arc<Codec<F>>,
+    path: std::path::PathBuf,
+    /// Stopped before the writer, which can't finish while it's reloading.
+    #[cfg(feature = "watch")]
+    watcher: Option<crate::watch::Watcher>,
+    writer: Writer,
+    recovered: Option<Recovered>,
+    on_panic: OnPanic,
+    validator: Option<Validator<T>>,
+    /// Held until the file is dropped, after the writer has finished.
+    _lock: Option<std::fs::File>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default, F: Format + Default> File<T, F> {
+    /// Create a new state file at the given path, see [`crate::File::new`].
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::builder(path).build_blocking()
+    }
+
+    /// Create a new state file with the given name in the service's state
+    /// directory, see [`crate::File::in_state_directory`].
+    pub fn in_state_directory(name: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::builder(state_directory()?.join(name)).build_blocking()
+    }
+
+    /// Configure how a state file at the given path is opened, finishing with
+    /// [`FileBuilder::build_blocking`].
+    pub fn builder(path: impl AsRef<Path>) -> FileBuilder<T, F> {
+        FileBuilder::new(path.as_ref().to_path_buf())
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Default, F: Format> FileBuilder<T, F> {
+    /// Open the state file as a [`blocking::File`](File), creating it if it
+    /// doesn't exist.
+    ///
+    /// If the file is upgraded by a migration or recovered from corruption it
+    /// is rewritten before returning.
+    pub fn build_blocking(self) -> Result<File<T, F>, Error> {
+        let opened = self.open()?;
+
+        #[allow(unused_mut)]
+        let mut file = File {
+            data: Arc::new(RwLock::new(opened.state)),
+            codec: Arc::new(opened.codec),
+            path: opened.path,
+            #[cfg(feature = "watch")]
+            watcher: None,
+            writer: opened.writer,
+            recovered: opened.recovered,
+            on_panic: opened.on_panic,
+            validator: opened.validator,
+            _lock: opened.lock,
+        };
+
+        #[cfg(feature = "watch")]
+        if let Some(watch) = opened.watch {
+            let watcher = watch(
+                file.path.clone(),
+                crate::watch::Shared::Blocking(file.data.clone()),
+                file.codec.clone(),
+                file.validator.clone(),
+                file.writer.reloader(),
+                Box::new(|| {}),
+            )?;
+            file.watcher = Some(watcher);
+        }
+
+        Ok(file)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Default, F: Format> File<T, F> {
+    /// Locks this state file with shared read access, blocking the current
+    /// thread until the lock has been acquired.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.data.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Locks this state file with exclusive write access, blocking the current
+    /// thread until the lock has been acquired.
+    pub fn write(&self) -> WriteGuard<'_, T, F> {
+        WriteGuard {
+            guard: self.data.write().unwrap_or_else(|e| e.into_inner()),
+            file: self,
+            progress: Progress::default(),
+        }
+    }
+
+    /// Change the state with `f`, writing it to disk if `f` succeeds, see
+    /// [`crate::File::update`].
+    pub fn update<R, E: From<Error>>(
+        &self,
+        f: impl FnOnce(&mut T) -> Result<R, E>,
+    ) -> Result<R, E> {
+        let mut write_guard = self.write();
+        // bypass the guard so it isn't written if `f` panics
+        let value = self.parts().update(&mut write_guard.guard, f)?;
+        write_guard.progress.changed();
+        write_guard.commit()?;
+        Ok(value)
+    }
+
+    /// Wait for all pending writes to reach the disk.
+    ///
+    /// Returns the first error from a write made by dropping a [`WriteGuard`]
+    /// since the last flush.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.writer.flush_blocking()
+    }
+
+    /// Limit how often dropped [`WriteGuard`]s are written to disk, see
+    /// [`crate::File::set_debounce`].
+    pub fn set_debounce(&self, interval: Option<Duration>) {
+        self.writer.debounce(interval);
+    }
+
+    /// Counters describing the writes made to this file so far.
+    pub fn stats(&self) -> Stats {
+        self.writer.stats()
+    }
+
+    /// List the backups of this file, newest first.
+    ///
+    /// Backups are only made if configured with [`FileBuilder::backups`].
+    pub fn backups(&self) -> Result<Vec<Backup>, Error> {
+        backup::list(&self.path)
+    }
+
+    /// Read the state stored in a backup.
+    pub fn load_backup(&self, backup: &Backup) -> Result<T, Error> {
+        let contents =
+            std::fs::read(backup.path()).map_err(|e| Error::io(backup.path(), "read", e))?;
+        Ok(self.codec.decode(backup.path(), &contents)?.state)
+    }
+
+    /// Re-encrypt the file under a new key, which is used from now on.
+    #[cfg(feature = "encryption")]
+    pub fn rotate_key(&self, key: Key) -> Result<(), Error> {
+        // hold the lock so no other writes are encrypted with the old key
+        let guard = self.data.write().unwrap_or_else(|e| e.into_inner());
+        let (encoded, old) = self.parts().replace_key(&guard, key)?;
+        let result = self.writer.save_blocking(encoded);
+
+        if result.is_err() {
+            // the file is still encrypted with the old key
+            self.parts().set_key(old);
+        }
+        result
+    }
+
+    /// Replace the state with the one stored in a backup.
+    ///
+    /// The current state is backed up in turn, so restoring can be undone.
+    pub fn restore(&self, backup: &Backup) -> Result<(), Error> {
+        let state = self.load_backup(backup)?;
+        let mut write_guard = self.write();
+        *write_guard = state;
+        write_guard.commit()
+    }
+
+    /// The action taken when opening the file to recover from it being
+    /// corrupt, see [`FileBuilder::recovery`].
+    pub fn recovered(&self) -> Option<&Recovered> {
+        self.recovered.as_ref()
+    }
+
+    /// Take the error from the most recent failed attempt to reload the file
+    /// after it was changed by another process, see [`FileBuilder::watch`].
+    #[cfg(feature = "watch")]
+    pub fn reload_error(&self) -> Option<Error> {
+        self.watcher.as_ref()?.take_error()
+    }
+
+    /// The path of the state file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn parts(&self) -> Parts<'_, T, F> {
+        Parts {
+            path: &self.path,
+            codec: &self.codec,
+            writer: &self.writer,
+            on_panic: self.on_panic,
+            validator: self.validator.as_ref(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backups, Lock, Recovery};
+    use serde::Deserialize;
+    use std::fs;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+    struct TestData {
+        field1: String,
+        field2: u32,
+    }
+
+    #[test]
+    fn test_blocking_create_and_write() {
+        let test_path = "test_blocking_create_and_write.json";
+        let file = File::<TestData>::new(test_path).unwrap();
+        assert_eq!(*file.read(), TestData::default());
+
+        let mut write_guard = file.write();
+        write_guard.field1 = "Test String".to_string();
+        write_guard.field2 = 42;
+        write_guard.commit().unwrap();
+
+        // unchanged guards aren't written
+        drop(file.write());
+        file.flush().unwrap();
+        assert_eq!(file.stats().writes, 1);
+        assert_eq!(file.stats().skipped, 1);
+
+        let result = File::<TestData>::new(test_path);
+        assert!(matches!(result, Err(Error::Locked { .. })));
+        drop(file);
+
+        let file = File::<TestData>::new(test_path).unwrap();
+        assert_eq!(file.read().field1, "Test String");
+        assert_eq!(file.read().field2, 42);
+        drop(file);
+
+        let _ = fs::remove_file(test_path); // Clean up test file
+        let _ = fs::remove_file(format!("{}.lock", test_path));
+    }
+
+    #[test]
+    fn test_blocking_update() {
+        let test_path = "test_blocking_update.json";
+        let file = File::<TestData>::new(test_path).unwrap();
+
+        file.update(|state| {
+            state.field2 = 1;
+            Ok::<_, Error>(())
+        })
+        .unwrap();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            file.update(|state| {
+                state.field2 = 2;
+                if state.field2 == 2 {
+                    panic!("update failed");
+                }
+                Ok::<_, Error>(())
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(file.read().field2, 1);
+
+        file.flush().unwrap();
+        assert_eq!(file.stats().writes, 1);
+        drop(file);
+
+        let _ = fs::remove_file(test_path); // Clean up test file
+        let _ = fs::remove_file(format!("{}.lock", test_path));
+    }
+
+    #[test]
+    fn test_blocking_write_guard_panic() {
+        let test_path = "test_blocking_write_guard_panic.json";
+        let file = File::<TestData>::new(test_path).unwrap();
+
+        let result = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let mut write_guard = file.write();
+                    write_guard.field2 = 1;
+                    panic!("mutation failed");
+                })
+                .join()
+        });
+        assert!(result.is_err());
+
+        assert_eq!(file.read().field2, 0);
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(test_path).unwrap(), "");
+        drop(file);
+
+        let _ = fs::remove_file(test_path); // Clean up test file
+        let _ = fs::remove_file(format!("{}.lock", test_path));
+    }
+
+    #[test]
+    fn test_blocking_validated() {
+        #[derive(Serialize, Deserialize, Default)]
+        struct Port(u16);
+
+        impl crate::Validate for Port {
+            fn validate(&self) -> Result<(), String> {
+                match self.0 {
+                    0 => Err("port must not be 0".to_string()),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let test_path = "test_blocking_validated.json";
+        let file = File::<Port>::builder(test_path)
+            .validated()
+            .build_blocking()
+            .unwrap();
+        file.write().0 = 8080;
+        file.flush().unwrap();
+
+        let mut write_guard = file.write();
+        write_guard.0 = 0;
+        assert!(matches!(write_guard.commit(), Err(Error::Invalid { .. })));
+        assert_eq!(file.read().0, 8080);
+        assert_eq!(fs::read_to_string(test_path).unwrap(), "8080");
+        drop(file);
+
+        let _ = fs::remove_file(test_path); // Clean up test file
+        let _ = fs::remove_file(format!("{}.lock", test_path));
+
+        // an invalid default state isn't written, so the file can be reopened
+        let open = |recovery| {
+            File::<Port>::builder(test_path)
+                .validated()
+                .persist_defaults(true)
+                .recovery(recovery)
+                .build_blocking()
+        };
+        drop(open(Recovery::Error).unwrap());
+        assert_eq!(fs::read_to_string(test_path).unwrap(), "");
+        let file = open(Recovery::Error).unwrap();
+        assert_eq!(file.read().0, 0);
+        drop(file);
+
+        fs::write(test_path, "{").unwrap();
+        let file = open(Recovery::Quarantine).unwrap();
+        let quarantined = match file.recovered() {
+            Some(Recovered::Quarantined { path, .. }) => path.clone().unwrap(),
+            _ => panic!("expected quarantine"),
+        };
+        drop(file);
+        assert_eq!(fs::read_to_string(test_path).unwrap(), "");
+        let file = open(Recovery::Quarantine).unwrap();
+        assert!(file.recovered().is_none());
+        drop(file);
+
+        let _ = fs::remove_file(quarantined);
+        let _ = fs::remove_file(test_path); // Clean up test file
+        let _ = fs::remove_file(format!("{}.lock", test_path));
+    }
+
+    #[test]
+    fn test_blocking_backups_and_recovery() {
+        let test_path = "test_blocking_backups_and_recovery.json";
+        let open = || {
+            File::<TestData>::builder(test_path)
+                .lock(Lock::None)
+                .backups(Backups::keep(2))
+                .recovery(Recovery::Backup)
+                .build_blocking()
+        };
+
+        let file = open().unwrap();
+        for i in 1..=2 {
+            let mut write_guard = file.write();
+            write_guard.field2 = i;
+            write_guard.commit().unwrap();
+        }
+        let backups = file.backups().unwrap();
+        assert_eq!(file.load_backup(&backups[0]).unwrap().field2, 1);
+        drop(file);
+
+        fs::write(test_path, "{").unwrap();
+        let file = open().unwrap();
+        assert_eq!(file.read().field2, 1);
+        let quarantined = match file.recovered() {
+            Some(Recovered::Backup { path, .. }) => path.clone().unwrap(),
+            _ => panic!("expected recovery from a backup"),
+        };
+        drop(file);
+
+        for backup in backups {
+            let _ = fs::remove_file(backup.path());
+        }
+        let _ = fs::remove_file(quarantined);
+        let _ = fs::remove_file(test_path); // Clean up test file
+    }
+
+    #[test]
+    fn test_blocking_in_state_directory() {
+        let test_dir = "test_blocking_in_state_directory";
+        // no other test reads the environment
+        std::env::set_var("STATE_DIRECTORY", format!("{}/state:other", test_dir));
+
+        let file = File::<TestData>::in_state_directory("state.json").unwrap();
+        assert_eq!(
+            file.path(),
+            Path::new("test_blocking_in_state_directory/state/state.json")
+        );
+        file.write().field2 = 1;
+        file.flush().unwrap();
+        assert!(file.path().exists());
+        drop(file);
+
+        std::env::remove_var("STATE_DIRECTORY");
+        let _ = fs::remove_dir_all(test_dir); // Clean up test files
+    }
+}
@@ -0,0 +1,486 @@
+use crate::codec::{Codec, Decoded};
+use crate::disk::{self, Fsync};
+use crate::format::{Format, Json, JsonCompact};
+use crate::validate::{self, Validator};
+use crate::writer::Writer;
+use crate::{
+    Backups, Compression, Error, Integrity, Lock, Migrations, OnPanic, Permissions, Recovered,
+    Recovery, Validate,
+};
+
+#[cfg(feature = "watch")]
+use crate::watch::{Shared, Watcher};
+#[cfg(feature = "watch")]
+use crate::writer::Reloader;
+#[cfg(feature = "encryption")]
+use crate::Key;
+#[cfg(feature = "async")]
+use crate::{File, ReadOnlyFile};
+#[cfg(feature = "async")]
+use async_lock::RwLock;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Starts a watcher for a state of type `T` stored in format `F`.
+#[cfg(feature = "watch")]
+pub(crate) type WatchFn<T, F> = fn(
+    PathBuf,
+    Shared<T>,
+    Arc<Codec<F>>,
+    Option<Validator<T>>,
+    Reloader,
+    Box<dyn Fn() + Send>,
+) -> Result<Watcher, Error>;
+
+/// What a state and format must be to be watched for changes, which only
+/// applies with the `watch` feature so that [`FileBuilder::format`] can keep
+/// the file watched.
+#[cfg(feature = "watch")]
+#[doc(hidden)]
+pub trait Watchable: Send + Sync + 'static {}
+
+#[cfg(feature = "watch")]
+impl<T: Send + Sync + 'static> Watchable for T {}
+
+#[cfg(not(feature = "watch"))]
+#[doc(hidden)]
+pub trait Watchable {}
+
+#[cfg(not(feature = "watch"))]
+impl<T> Watchable for T {}
+
+/// A state file that has been opened, ready to be accessed through a `File`.
+pub(crate) struct Opened<T, F> {
+    pub(crate) state: T,
+    pub(crate) path: PathBuf,
+    pub(crate) codec: Codec<F>,
+    pub(crate) writer: Writer,
+    pub(crate) recovered: Option<Recovered>,
+    pub(crate) on_panic: OnPanic,
+    pub(crate) validator: Option<Validator<T>>,
+    pub(crate) lock: Option<std::fs::File>,
+    #[cfg(feature = "watch")]
+    pub(crate) watch: Option<WatchFn<T, F>>,
+}
+
+/// Options for opening a [`File`](crate::File), created with
+/// [`File::builder`](crate::File::builder).
+///
+/// Open the file with [`FileBuilder::build`], or with
+/// [`FileBuilder::build_blocking`] for a [`blocking::File`](crate::blocking::File).
+///
+/// ```rust
+/// use statefile::blocking::File;
+/// use statefile::Lock;
+/// use std::time::Duration;
+///
+/// let state = File::<u32>::builder("builder.json")
+///     .lock(Lock::Wait(Duration::from_secs(5)))
+///     .build_blocking()
+///     .unwrap();
+/// # drop(state);
+/// # std::fs::remove_file("builder.json").unwrap();
+/// # std::fs::remove_file("builder.json.lock").unwrap();
+/// ```
+pub struct FileBuilder<T, F = Json> {
+    path: PathBuf,
+    codec: Codec<F>,
+    lock: Lock,
+    backups: Option<Backups>,
+    recovery: Recovery,
+    permissions: Permissions,
+    create: bool,
+    persist_defaults: bool,
+    fsync: Fsync,
+    debounce: Option<Duration>,
+    on_panic: OnPanic,
+    validator: Option<Validator<T>>,
+    #[cfg(feature = "watch")]
+    watch: Option<WatchFn<T, F>>,
+    _state: PhantomData<fn() -> T>,
+}
+
+impl<T, F: Format + Default> FileBuilder<T, F> {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        FileBuilder {
+            path,
+            codec: Codec::new(F::default()),
+            lock: Lock::default(),
+            backups: None,
+            recovery: Recovery::default(),
+            permissions: Permissions::default(),
+            create: true,
+            persist_defaults: false,
+            fsync: Fsync::default(),
+            debounce: None,
+            on_panic: OnPanic::default(),
+            validator: None,
+            #[cfg(feature = "watch")]
+            watch: None,
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Default, F: Format> FileBuilder<T, F> {
+    /// Store the file in the given format instead. Options set before this,
+    /// including [`FileBuilder::watch`], are kept.
+    pub fn format<G: Format + Watchable>(self, format: G) -> FileBuilder<T, G>
+    where
+        T: Watchable,
+    {
+        FileBuilder {
+            path: self.path,
+            codec: Codec {
+                format,
+                migrations: self.codec.migrations,
+                integrity: self.codec.integrity,
+                compression: self.codec.compression,
+                #[cfg(feature = "encryption")]
+                key: self.codec.key,
+            },
+            lock: self.lock,
+            backups: self.backups,
+            recovery: self.recovery,
+            permissions: self.permissions,
+            create: self.create,
+            persist_defaults: self.persist_defaults,
+            fsync: self.fsync,
+            debounce: self.debounce,
+            on_panic: self.on_panic,
+            validator: self.validator,
+            // the watcher is specific to the format
+            #[cfg(feature = "watch")]
+            watch: self.watch.map(|_| Watcher::spawn::<T, G> as WatchFn<T, G>),
+            _state: PhantomData,
+        }
+    }
+
+    /// Whether to create the file if it doesn't exist, otherwise opening a
+    /// missing file fails. Defaults to `true`.
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Write the default state as soon as the file is created, or opened while
+    /// empty, rather than on the first write. Defaults to `false`.
+    pub fn persist_defaults(mut self, persist_defaults: bool) -> Self {
+        self.persist_defaults = persist_defaults;
+        self
+    }
+
+    /// Set when writes are synced to the storage device. Defaults to
+    /// [`Fsync::Always`].
+    pub fn fsync(mut self, fsync: Fsync) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Set how often dropped guards are written to disk, see
+    /// [`File::set_debounce`](crate::File::set_debounce).
+    pub fn debounce(mut self, interval: Duration) -> Self {
+        self.debounce = Some(interval);
+        self
+    }
+
+    /// Set what happens to changes made through a write guard that is dropped
+    /// while its thread panics. Defaults to [`OnPanic::Rollback`].
+    pub fn on_panic(mut self, on_panic: OnPanic) -> Self {
+        self.on_panic = on_panic;
+        self
+    }
+
+    /// Check the state with `validate` when the file is opened and before each
+    /// write, rejecting states it returns an error for.
+    ///
+    /// A file that fails validation when opened is treated as corrupt, see
+    /// [`FileBuilder::recovery`]. A guard whose changes fail validation is
+    /// rolled back: [`WriteGuard::commit`](crate::WriteGuard::commit) returns
+    /// [`Error::Invalid`], while the error from a dropped guard is returned by
+    /// [`File::flush`](crate::File::flush).
+    ///
+    /// The default state used for new, empty or quarantined files isn't
+    /// validated when it's used, but it's only written to disk if it passes,
+    /// so that the file can be opened again. Otherwise the file is left empty
+    /// until the state is changed.
+    pub fn validate(
+        mut self,
+        validate: impl Fn(&T) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.validator = Some(Arc::new(validate));
+        self
+    }
+
+    /// Check the state with its [`Validate`] implementation, see
+    /// [`FileBuilder::validate`].
+    pub fn validated(self) -> Self
+    where
+        T: Validate + 'static,
+    {
+        self.validate(T::validate)
+    }
+
+    /// Set the permission bits of the file, see [`Permissions::mode`].
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.permissions = self.permissions.mode(mode);
+        self
+    }
+
+    /// Set how the file is locked against other processes. Defaults to
+    /// [`Lock::FailFast`].
+    pub fn lock(mut self, lock: Lock) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    /// Store the file with a schema version, upgrading older files when they're
+    /// opened.
+    pub fn migrations(mut self, migrations: Migrations) -> Self {
+        self.codec.migrations = Some(migrations);
+        self
+    }
+
+    /// Keep previous versions of the file when it's overwritten.
+    pub fn backups(mut self, backups: Backups) -> Self {
+        self.backups = Some(backups);
+        self
+    }
+
+    /// Set what happens when the file can't be deserialized. Defaults to
+    /// [`Recovery::Error`].
+    pub fn recovery(mut self, recovery: Recovery) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Store a checksum with the state, which is verified when the file is
+    /// opened. A mismatch or a missing checksum is treated as corruption, see
+    /// [`FileBuilder::recovery`].
+    pub fn integrity(mut self, integrity: Integrity) -> Self {
+        self.codec.integrity = Some(integrity);
+        self
+    }
+
+    /// Compress the file when it's written. Existing uncompressed files are
+    /// still read, and compressed when next written.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.codec.compression = Some(compression);
+        self
+    }
+
+    /// Set the permissions and ownership of the file, which are applied each
+    /// time it's written.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Encrypt the file with the given key.
+    ///
+    /// Existing unencrypted files are encrypted when opened. Backups made
+    /// before then, or before the key is rotated with
+    /// [`File::rotate_key`](crate::File::rotate_key), aren't re-encrypted.
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, key: Key) -> Self {
+        self.codec.key = std::sync::RwLock::new(Some(key));
+        self
+    }
+
+    /// Reload the state when the file is changed by another process, e.g.
+    /// edited by hand.
+    ///
+    /// The state is replaced under the write lock, discarding any changes
+    /// that haven't been written yet. Writes made by this `File` are ignored.
+    /// If the new contents can't be read the state is left as it is, and the
+    /// error is returned by [`File::reload_error`](crate::File::reload_error).
+    /// An empty file is assumed to still be being written and is also ignored.
+    #[cfg(feature = "watch")]
+    pub fn watch(mut self) -> Self
+    where
+        T: Send + Sync + 'static,
+        F: Send + Sync + 'static,
+    {
+        self.watch = Some(Watcher::spawn::<T, F>);
+        self
+    }
+
+    /// Open the state file, creating it if it doesn't exist.
+    ///
+    /// If the file is upgraded by a migration or recovered from corruption it
+    /// is rewritten before returning.
+    #[cfg(feature = "async")]
+    pub async fn build(self) -> Result<File<T, F>, Error>
+    where
+        T: Send + 'static,
+        F: Send + 'static,
+    {
+        let path = self.path.clone();
+        let opened = disk::unblock(&path, move || self.open()).await?;
+
+        #[allow(unused_mut)]
+        let mut file = File {
+            data: Arc::new(RwLock::new(opened.state)),
+            path: opened.path,
+            codec: Arc::new(opened.codec),
+            changed: Arc::default(),
+            #[cfg(feature = "watch")]
+            watcher: None,
+            writer: opened.writer,
+            recovered: opened.recovered,
+            on_panic: opened.on_panic,
+            validator: opened.validator,
+            _lock: opened.lock,
+        };
+
+        #[cfg(feature = "watch")]
+        if let Some(watch) = opened.watch {
+            let changed = file.changed.clone();
+            let watcher = watch(
+                file.path.clone(),
+                Shared::Async(file.data.clone()),
+                file.codec.clone(),
+                file.validator.clone(),
+                file.writer.reloader(),
+                Box::new(move || changed.notify()),
+            )?;
+            file.watcher = Some(watcher);
+        }
+
+        Ok(file)
+    }
+
+    /// Open the existing state file for reading only, see
+    /// [`File::open_read_only`].
+    ///
+    /// The file is decoded with the format, migrations, integrity,
+    /// compression and encryption options, and checked by the validator.
+    /// Options that affect writing, locking or recovery are ignored, and a
+    /// file that can't be read is an error.
+    #[cfg(feature = "async")]
+    pub async fn build_read_only(self) -> Result<ReadOnlyFile<T, F>, Error>
+    where
+        T: Send + 'static,
+        F: Send + 'static,
+    {
+        let path = self.path.clone();
+        let state = disk::unblock(&path, move || self.read()).await?;
+        Ok(ReadOnlyFile::new(state, path))
+    }
+
+    /// Read and decode the existing file without creating or locking it,
+    /// blocking the current thread.
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    fn read(self) -> Result<T, Error> {
+        let path = self.path;
+        let contents = std::fs::read(&path).map_err(|e| Error::io(&path, "open", e))?;
+        let decoded = self.codec.decode(&path, &contents)?;
+        if !contents.is_empty() {
+            validate::check(self.validator.as_ref(), &path, &decoded.state)?;
+        }
+        Ok(decoded.state)
+    }
+
+    /// Lock, read and decode the file, blocking the current thread.
+    pub(crate) fn open(self) -> Result<Opened<T, F>, Error> {
+        let path = self.path;
+        let lock = self.lock;
+        let read_only = lock.is_read_only();
+
+        if !read_only && self.create {
+            self.permissions.create_parent(&path)?;
+        } else if !self.create {
+            // before locking, so a missing file doesn't leave a lock file behind
+            std::fs::metadata(&path).map_err(|e| Error::io(&path, "open", e))?;
+        }
+        let lock_file = lock.acquire(&path)?;
+        let contents = if !self.create {
+            std::fs::read(&path).map_err(|e| Error::io(&path, "open", e))?
+        } else if read_only {
+            disk::read_if_exists(&path)?
+        } else {
+            disk::read_or_create(&path, &self.permissions)?
+        };
+        self.permissions.check(&path, read_only)?;
+
+        let codec = self.codec;
+        let result = codec.decode(&path, &contents).and_then(|decoded| {
+            if !contents.is_empty() {
+                validate::check(self.validator.as_ref(), &path, &decoded.state)?;
+            }
+            Ok(decoded)
+        });
+        let (mut decoded, recovered) = match result {
+            Ok(mut decoded) => {
+                decoded.rewrite |= contents.is_empty() && self.persist_defaults;
+                (decoded, None)
+            }
+            Err(e) => {
+                let (state, recovered) =
+                    self.recovery
+                        .recover(&codec, self.validator.as_ref(), &path, e, !read_only)?;
+                let decoded = Decoded {
+                    state,
+                    fingerprint: None,
+                    rewrite: true,
+                };
+                (decoded, Some(recovered))
+            }
+        };
+
+        // a default state the validator rejects would fail to open next time
+        if decoded.rewrite && !read_only {
+            if let Err(e) = validate::check(self.validator.as_ref(), &path, &decoded.state) {
+                log::warn!("Not writing the default state: {}", e);
+                decoded.rewrite = false;
+                if recovered.is_some() {
+                    // replace the quarantined file with an empty one
+                    disk::read_or_create(&path, &self.permissions)?;
+                }
+            }
+        }
+
+        let writer = Writer::spawn(
+            path.clone(),
+            decoded.fingerprint,
+            read_only,
+            self.backups,
+            self.permissions,
+            self.fsync,
+        )?;
+        if self.debounce.is_some() {
+            writer.debounce(self.debounce);
+        }
+
+        if decoded.rewrite && !read_only {
+            let mut encoded = codec.encode(&decoded.state)?;
+            encoded.force = true;
+            writer.save_blocking(encoded)?;
+        }
+
+        Ok(Opened {
+            state: decoded.state,
+            path,
+            codec,
+            writer,
+            recovered,
+            on_panic: self.on_panic,
+            validator: self.validator,
+            lock: lock_file,
+            #[cfg(feature = "watch")]
+            watch: self.watch,
+        })
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Default + Watchable> FileBuilder<T, Json> {
+    /// Store the file as JSON without any whitespace rather than pretty
+    /// printed, see [`JsonCompact`].
+    pub fn compact(self) -> FileBuilder<T, JsonCompact> {
+        self.format(JsonCompact)
+    }
+}
@@ -0,0 +1,148 @@
+use crate::format::Format;
+use crate::migrate::Envelope;
+use crate::{compression, integrity, Compression, Error, Integrity, Migrations};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+#[cfg(feature = "encryption")]
+use crate::encryption::{self, Key};
+#[cfg(feature = "encryption")]
+use std::sync::RwLock;
+
+/// Converts between the in-memory state and the bytes stored on disk.
+pub(crate) struct Codec<F> {
+    pub(crate) format: F,
+    pub(crate) migrations: Option<Migrations>,
+    pub(crate) integrity: Option<Integrity>,
+    pub(crate) compression: Option<Compression>,
+    #[cfg(feature = "encryption")]
+    pub(crate) key: RwLock<Option<Key>>,
+}
+
+/// The state converted to the bytes stored on disk.
+pub(crate) struct Encoded {
+    pub(crate) bytes: Vec<u8>,
+    /// Identifies the serialized state, ignoring any encryption.
+    pub(crate) fingerprint: u64,
+    /// Whether the bytes must be written even if the state is unchanged.
+    pub(crate) force: bool,
+}
+
+/// The state read from disk.
+pub(crate) struct Decoded<T> {
+    pub(crate) state: T,
+    /// The fingerprint the state had when it was encoded, if known.
+    pub(crate) fingerprint: Option<u64>,
+    /// Whether the file should be rewritten to bring it up to date, e.g.
+    /// because it was migrated from an older version.
+    pub(crate) rewrite: bool,
+}
+
+impl<F: Format> Codec<F> {
+    pub(crate) fn new(format: F) -> Self {
+        Codec {
+            format,
+            migrations: None,
+            integrity: None,
+            compression: None,
+            #[cfg(feature = "encryption")]
+            key: RwLock::new(None),
+        }
+    }
+
+    /// Convert data structure to the bytes stored on disk.
+    pub(crate) fn encode<T: Serialize>(&self, state: &T) -> Result<Encoded, Error> {
+        let result = match &self.migrations {
+            Some(migrations) => self.format.serialize(&Envelope {
+                version: migrations.version(),
+                state,
+            }),
+            None => self.format.serialize(state),
+        };
+        let payload = result.map_err(Error::Serialize)?;
+        let fingerprint = fingerprint(&payload);
+
+        let bytes = match self.integrity {
+            Some(integrity) => integrity.seal(payload),
+            None => payload,
+        };
+
+        let bytes = match self.compression {
+            Some(compression) => compression.compress(bytes)?,
+            None => bytes,
+        };
+
+        #[cfg(feature = "encryption")]
+        let bytes = match &*self.key.read().unwrap_or_else(|e| e.into_inner()) {
+            Some(key) => key.seal(&bytes)?,
+            None => bytes,
+        };
+
+        Ok(Encoded {
+            bytes,
+            fingerprint,
+            force: false,
+        })
+    }
+
+    /// Convert the contents of the file at `path` to the state, treating an
+    /// empty file as the default state.
+    pub(crate) fn decode<T: DeserializeOwned + Default>(
+        &self,
+        path: &Path,
+        contents: &[u8],
+    ) -> Result<Decoded<T>, Error> {
+        if contents.is_empty() {
+            return Ok(Decoded {
+                state: T::default(),
+                fingerprint: None,
+                rewrite: false,
+            });
+        }
+
+        #[cfg(feature = "encryption")]
+        let decrypted;
+        #[cfg(feature = "encryption")]
+        let (contents, unencrypted) = {
+            let key = self.key.read().unwrap_or_else(|e| e.into_inner());
+            let unencrypted = key.is_some() && !encryption::is_encrypted(contents);
+            decrypted = encryption::open(path, key.as_ref(), contents)?;
+            (&*decrypted, unencrypted)
+        };
+        #[cfg(not(feature = "encryption"))]
+        let unencrypted = false;
+
+        let decompressed = compression::decompress(path, contents)?;
+        let payload = integrity::open(path, &decompressed, self.integrity.is_some())?;
+
+        let (state, upgraded) = match &self.migrations {
+            Some(migrations) => {
+                let (state, version) = migrations.load(&self.format, path, payload)?;
+                (state, version < migrations.version())
+            }
+            None => {
+                let state = self
+                    .format
+                    .deserialize(payload)
+                    .map_err(|e| Error::deserialize(path, e))?;
+                (state, false)
+            }
+        };
+
+        Ok(Decoded {
+            state,
+            fingerprint: Some(fingerprint(payload)),
+            // plaintext should be encrypted as soon as possible
+            rewrite: upgraded || unencrypted,
+        })
+    }
+}
+
+fn fingerprint(payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
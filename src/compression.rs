@@ -0,0 +1,152 @@
+//! Compression of the bytes stored on disk.
+//!
+//! Compressed files are recognised by the magic bytes of the compression
+//! format, so no header of our own is needed and files can be inspected with
+//! the usual command line tools.
+
+use crate::format::DecodeError;
+use crate::Error;
+use std::borrow::Cow;
+use std::path::Path;
+
+/// Magic bytes at the start of a zstd frame.
+const ZSTD_MAGIC: &[u8; 4] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Magic bytes at the start of a gzip member.
+const GZIP_MAGIC: &[u8; 2] = &[0x1f, 0x8b];
+
+/// An algorithm used to compress a state file.
+///
+/// Files are decompressed when they're opened if they start with the magic
+/// bytes of a supported algorithm, whether or not compression is configured.
+/// Uncompressed files are accepted, and are compressed when next written.
+///
+/// Serialization formats that aren't self-describing, like
+/// [`Bincode`](crate::format::Bincode), may produce state that happens to start
+/// with the same bytes. Enable an [`Integrity`](crate::Integrity) check to rule
+/// this out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// [Zstandard](https://facebook.github.io/zstd/) at the default level.
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// gzip at the default level.
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl Compression {
+    /// Compress the bytes written to disk.
+    pub(crate) fn compress(self, bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+        // unused when no algorithm is enabled
+        let _ = &bytes;
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                zstd::encode_all(&bytes[..], 0).map_err(|e| Error::Serialize(e.into()))
+            }
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(&bytes)
+                    .and_then(|()| encoder.finish())
+                    .map_err(|e| Error::Serialize(e.into()))
+            }
+        }
+    }
+}
+
+/// Decompress the file at `path` if it's compressed.
+pub(crate) fn decompress<'a>(path: &Path, contents: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+    let result = if contents.starts_with(ZSTD_MAGIC) {
+        zstd_decode(contents)
+    } else if contents.starts_with(GZIP_MAGIC) {
+        gzip_decode(contents)
+    } else {
+        return Ok(Cow::Borrowed(contents));
+    };
+
+    result
+        .map(Cow::Owned)
+        .map_err(|e| Error::deserialize(path, e))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decode(contents: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    zstd::decode_all(contents).map_err(DecodeError::new)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decode(_: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    Err(DecodeError::new(
+        "file is compressed with zstd, which requires the `zstd` feature",
+    ))
+}
+
+#[cfg(feature = "gzip")]
+fn gzip_decode(contents: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    use std::io::Read;
+
+    let mut bytes = Vec::new();
+    flate2::read::GzDecoder::new(contents)
+        .read_to_end(&mut bytes)
+        .map_err(DecodeError::new)?;
+    Ok(bytes)
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip_decode(_: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    Err(DecodeError::new(
+        "file is compressed with gzip, which requires the `gzip` feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress_uncompressed() {
+        let path = Path::new("state.json");
+        assert_eq!(&*decompress(path, b"{}").unwrap(), b"{}");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd() {
+        let path = Path::new("state.json");
+        let payload = b"{\"a\":1}".repeat(100);
+        let mut bytes = Compression::Zstd.compress(payload.clone()).unwrap();
+        assert!(bytes.starts_with(ZSTD_MAGIC));
+        assert!(bytes.len() < payload.len());
+        assert_eq!(&*decompress(path, &bytes).unwrap(), &payload[..]);
+
+        bytes.truncate(bytes.len() / 2);
+        assert!(matches!(
+            decompress(path, &bytes),
+            Err(Error::Deserialize { .. })
+        ));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip() {
+        let path = Path::new("state.json");
+        let payload = b"{\"a\":1}".repeat(100);
+        let mut bytes = Compression::Gzip.compress(payload.clone()).unwrap();
+        assert!(bytes.starts_with(GZIP_MAGIC));
+        assert!(bytes.len() < payload.len());
+        assert_eq!(&*decompress(path, &bytes).unwrap(), &payload[..]);
+
+        bytes.truncate(bytes.len() / 2);
+        assert!(matches!(
+            decompress(path, &bytes),
+            Err(Error::Deserialize { .. })
+        ));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{Error, Permissions};
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// Run blocking file operations on the blocking thread pool to avoid stalling
+/// the runtime.
+///
+/// Within a tokio runtime its pool is used, otherwise that of async-std or
+/// smol, whichever is enabled.
+#[cfg(feature = "async")]
+#[cfg_attr(not(feature = "tokio"), allow(unused_variables))]
+pub(crate) async fn unblock<R: Send + 'static>(
+    path: &Path,
+    f: impl FnOnce() -> Result<R, Error> + Send + 'static,
+) -> Result<R, Error> {
+    #[cfg(feature = "tokio")]
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        return runtime
+            .spawn_blocking(f)
+            .await
+            .map_err(|e| Error::io(path, "access", std::io::Error::other(e)))?;
+    }
+
+    #[cfg(feature = "async-std")]
+    return async_std::task::spawn_blocking(f).await;
+    #[cfg(all(feature = "smol", not(feature = "async-std")))]
+    return ::blocking::unblock(f).await;
+    // panics outside of a tokio runtime, like the rest of tokio
+    #[cfg(not(any(feature = "async-std", feature = "smol")))]
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::io(path, "access", std::io::Error::other(e)))?
+}
+
+#[cfg(all(
+    feature = "async",
+    not(any(feature = "tokio", feature = "async-std", feature = "smol"))
+))]
+compile_error!("the `async` feature requires one of `tokio`, `async-std` or `smol`");
+
+/// When writes are synced to the storage device, trading durability for
+/// speed.
+///
+/// Whatever the policy, a write replaces the file atomically so other
+/// processes never see it partially written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fsync {
+    /// Sync the file and the directory containing it, so a completed write
+    /// survives a power loss. The default.
+    #[default]
+    Always,
+    /// Sync the file but not its directory, so after a power loss it may
+    /// contain the previous state.
+    FileOnly,
+    /// Leave syncing to the operating system. After a power loss the file may
+    /// contain the previous state or, on some filesystems, be empty.
+    Never,
+}
+
+/// Read the file at `path`, creating it with `permissions` if it doesn't
+/// exist.
+pub(crate) fn read_or_create(path: &Path, permissions: &Permissions) -> Result<Vec<u8>, Error> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    permissions.create_mode(&mut options);
+
+    let mut file = match options.open(path) {
+        Ok(file) => {
+            permissions.apply(&file, path, path)?;
+            file
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| Error::io(path, "open", e))?,
+        Err(e) => return Err(Error::io(path, "open", e)),
+    };
+
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)
+        .map_err(|e| Error::io(path, "read", e))?;
+
+    Ok(contents)
+}
+
+/// Read the file at `path`, treating a missing file as empty.
+pub(crate) fn read_if_exists(path: &Path) -> Result<Vec<u8>, Error> {
+    match std::fs::read(path) {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(Error::io(path, "read", e)),
+    }
+}
+
+/// Whether the file at `path` has no contents.
+pub(crate) fn is_empty(path: &Path) -> Result<bool, Error> {
+    let metadata = std::fs::metadata(path).map_err(|e| Error::io(path, "stat", e))?;
+    Ok(metadata.len() == 0)
+}
+
+/// Atomically replace the contents of the file at `path`.
+///
+/// The data is written to a temporary sibling file which is synced to disk and
+/// then renamed over the target. Finally the parent directory is synced so the
+/// rename itself is durable. At any point the file on disk contains either the
+/// old or the new contents, never a mix of both. `fsync` can skip the syncs.
+///
+/// The temporary file is given `permissions` before anything is written to it.
+pub(crate) fn write_atomic(
+    path: &Path,
+    contents: &[u8],
+    permissions: &Permissions,
+    fsync: Fsync,
+) -> Result<(), Error> {
+    let tmp_path = sibling(path, ".tmp");
+
+    let result = (|| {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        permissions.create_mode(&mut options);
+        let mut file = options
+            .open(&tmp_path)
+            .map_err(|e| Error::io(&tmp_path, "create", e))?;
+        permissions.apply(&file, &tmp_path, path)?;
+        file.write_all(contents)
+            .map_err(|e| Error::io(&tmp_path, "write", e))?;
+        if fsync != Fsync::Never {
+            file.sync_all()
+                .map_err(|e| Error::io(&tmp_path, "sync", e))?;
+        }
+        drop(file);
+
+        std::fs::rename(&tmp_path, path).map_err(|e| Error::io(path, "rename", e))?;
+        match fsync {
+            Fsync::Always => sync_parent(path),
+            Fsync::FileOnly | Fsync::Never => Ok(()),
+        }
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+/// Path of the file next to `path` with `suffix` appended to its name.
+pub(crate) fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// The directory containing `path`.
+pub(crate) fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+/// Sync the directory containing `path` so a rename within it is durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<(), Error> {
+    let parent = parent(path);
+    std::fs::File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| Error::io(parent, "sync", e))
+}
+
+/// Directories can't be opened for syncing on this platform.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
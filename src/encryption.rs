@@ -0,0 +1,222 @@
+//! Encryption of state files at rest.
+//!
+//! An encrypted file starts with [`MAGIC`], a byte identifying the cipher and
+//! the nonce, followed by the ciphertext. The header is authenticated along
+//! with the ciphertext.
+
+use crate::Error;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::borrow::Cow;
+use std::fmt;
+use std::path::Path;
+
+/// Marks the start of an encrypted file.
+const MAGIC: &[u8; 4] = b"SFEN";
+
+/// Identifies XChaCha20-Poly1305, the only supported cipher.
+const XCHACHA20_POLY1305: u8 = 1;
+
+const NONCE_LEN: usize = 24;
+
+/// A 256-bit key used to encrypt state files with XChaCha20-Poly1305.
+///
+/// Keys stored in files or the environment may be either 32 raw bytes or 64
+/// hexadecimal characters, with surrounding whitespace ignored.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Use 32 raw bytes as the key.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Key(bytes)
+    }
+
+    /// Read the key from a file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = std::fs::read(path).map_err(|e| Error::io(path, "read key", e))?;
+        Self::parse(&contents)
+    }
+
+    /// Read the key from an environment variable.
+    pub fn from_env(name: &str) -> Result<Self, Error> {
+        let value = std::env::var(name).map_err(|e| Error::Key {
+            reason: format!("{}: {}", name, e),
+        })?;
+        Self::parse(value.as_bytes())
+    }
+
+    /// Read the key from a credential passed by systemd with `LoadCredential=`
+    /// or `SetCredential=`.
+    pub fn from_credential(name: &str) -> Result<Self, Error> {
+        let dir = std::env::var_os("CREDENTIALS_DIRECTORY").ok_or_else(|| Error::Key {
+            reason: "CREDENTIALS_DIRECTORY is not set".to_string(),
+        })?;
+        Self::from_file(Path::new(&dir).join(name))
+    }
+
+    fn parse(contents: &[u8]) -> Result<Self, Error> {
+        if let Ok(bytes) = <[u8; 32]>::try_from(contents) {
+            return Ok(Key(bytes));
+        }
+
+        let hex = contents.trim_ascii();
+        let invalid = || Error::Key {
+            reason: "expected 32 bytes or 64 hexadecimal characters".to_string(),
+        };
+        if hex.len() != 64 {
+            return Err(invalid());
+        }
+
+        let mut bytes = [0; 32];
+        for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(Key(bytes))
+    }
+
+    /// Encrypt `plaintext` with a random nonce.
+    pub(crate) fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let cipher = XChaCha20Poly1305::new(&self.0.into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + NONCE_LEN + plaintext.len() + 16);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(XCHACHA20_POLY1305);
+        bytes.extend_from_slice(&nonce);
+
+        let payload = Payload {
+            msg: plaintext,
+            aad: &bytes,
+        };
+        let ciphertext = cipher.encrypt(&nonce, payload).map_err(|_| Error::Key {
+            reason: "encryption failed".to_string(),
+        })?;
+
+        bytes.extend_from_slice(&ciphertext);
+        Ok(bytes)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Whether the file contents are encrypted.
+pub(crate) fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+/// Decrypt the file at `path` if it's encrypted, returning the plaintext.
+pub(crate) fn open<'a>(
+    path: &Path,
+    key: Option<&Key>,
+    contents: &'a [u8],
+) -> Result<Cow<'a, [u8]>, Error> {
+    if !is_encrypted(contents) {
+        return Ok(Cow::Borrowed(contents));
+    }
+
+    let error = |reason: &str| Error::Encryption {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+    };
+
+    let key = key.ok_or_else(|| error("no key configured"))?;
+    let header_len = MAGIC.len() + 1 + NONCE_LEN;
+    if contents.len() < header_len {
+        return Err(error("truncated header"));
+    }
+    if contents[MAGIC.len()] != XCHACHA20_POLY1305 {
+        return Err(error("unsupported cipher"));
+    }
+
+    let (header, ciphertext) = contents.split_at(header_len);
+    let nonce = XNonce::from_slice(&header[MAGIC.len() + 1..]);
+    let cipher = XChaCha20Poly1305::new(&key.0.into());
+    let payload = Payload {
+        msg: ciphertext,
+        aad: header,
+    };
+    let plaintext = cipher
+        .decrypt(nonce, payload)
+        .map_err(|_| error("wrong key or tampered contents"))?;
+
+    Ok(Cow::Owned(plaintext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn key() -> Key {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        Key::from_bytes(bytes)
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let path = Path::new("state.json");
+        let sealed = key().seal(b"{\"token\":\"secret\"}").unwrap();
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(
+            open(path, Some(&key()), &sealed).unwrap().as_ref(),
+            b"{\"token\":\"secret\"}"
+        );
+
+        // plaintext passes through
+        assert_eq!(open(path, None, b"{}").unwrap().as_ref(), b"{}");
+    }
+
+    #[test]
+    fn test_open_invalid() {
+        let path = Path::new("state.json");
+        let mut sealed = key().seal(b"{}").unwrap();
+
+        let other = Key::from_bytes([1; 32]);
+        assert!(matches!(
+            open(path, Some(&other), &sealed),
+            Err(Error::Encryption { .. })
+        ));
+        assert!(matches!(
+            open(path, None, &sealed),
+            Err(Error::Encryption { .. })
+        ));
+
+        // the header is authenticated too
+        sealed[10] ^= 1;
+        assert!(matches!(
+            open(path, Some(&key()), &sealed),
+            Err(Error::Encryption { .. })
+        ));
+    }
+
+    #[test]
+    fn test_key_sources() {
+        let test_path = "test_key_sources.key";
+        std::fs::write(test_path, format!("{}\n", HEX)).unwrap();
+        assert_eq!(Key::from_file(test_path).unwrap(), key());
+        std::fs::write(test_path, key().0).unwrap();
+        assert_eq!(Key::from_file(test_path).unwrap(), key());
+        let _ = std::fs::remove_file(test_path); // Clean up test file
+
+        std::env::set_var("STATEFILE_TEST_KEY", HEX);
+        assert_eq!(Key::from_env("STATEFILE_TEST_KEY").unwrap(), key());
+        std::env::set_var("STATEFILE_TEST_KEY", "abc");
+        assert!(matches!(
+            Key::from_env("STATEFILE_TEST_KEY"),
+            Err(Error::Key { .. })
+        ));
+        std::env::remove_var("STATEFILE_TEST_KEY");
+    }
+}
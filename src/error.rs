@@ -0,0 +1,222 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::format::DecodeError;
+
+pub(crate) type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Errors that can occur while loading or persisting a state file.
+///
+/// New variants may be added as features are, so matches need a wildcard arm.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// An I/O operation on the file failed.
+    Io {
+        /// The file or directory being operated on.
+        path: PathBuf,
+        /// The operation that failed, e.g. `"open"` or `"rename"`.
+        op: &'static str,
+        source: io::Error,
+    },
+    /// The file contents could not be deserialized.
+    Deserialize {
+        path: PathBuf,
+        /// Line of the file where the error occurred, starting at 1. Only
+        /// text formats report a position.
+        line: Option<usize>,
+        /// Column of the line where the error occurred, starting at 1.
+        column: Option<usize>,
+        source: BoxError,
+    },
+    /// The state could not be serialized.
+    Serialize(BoxError),
+    /// The file's checksum doesn't match its contents.
+    Integrity { path: PathBuf, reason: String },
+    /// The file couldn't be decrypted.
+    Encryption { path: PathBuf, reason: String },
+    /// An encryption key couldn't be loaded or used.
+    Key { reason: String },
+    /// The state was rejected by validation, see
+    /// [`FileBuilder::validate`](crate::FileBuilder::validate).
+    Invalid { path: PathBuf, reason: String },
+    /// The file is locked by another process.
+    Locked { path: PathBuf },
+    /// The file was opened read-only and can't be written.
+    ReadOnly { path: PathBuf },
+    /// The file is more permissive than the configured mode.
+    Permissions {
+        path: PathBuf,
+        /// The permission bits of the file.
+        mode: u32,
+        /// The most permissive mode allowed.
+        required: u32,
+    },
+    /// No directory to keep state in could be found, see
+    /// [`state_directory`](crate::state_directory).
+    StateDirectory { reason: String },
+    /// Migrating the file from an older schema version failed.
+    Migration {
+        path: PathBuf,
+        /// The schema version being migrated from.
+        version: u32,
+        reason: String,
+    },
+}
+
+impl Error {
+    pub(crate) fn io(path: impl AsRef<Path>, op: &'static str, source: io::Error) -> Self {
+        Error::Io {
+            path: path.as_ref().to_path_buf(),
+            op,
+            source,
+        }
+    }
+
+    pub(crate) fn deserialize(path: impl AsRef<Path>, error: DecodeError) -> Self {
+        Error::Deserialize {
+            path: path.as_ref().to_path_buf(),
+            line: error.position.map(|(line, _)| line),
+            column: error.position.map(|(_, column)| column),
+            source: error.source,
+        }
+    }
+
+    /// The path of the file this error relates to, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Io { path, .. }
+            | Error::Deserialize { path, .. }
+            | Error::Integrity { path, .. }
+            | Error::Encryption { path, .. }
+            | Error::Invalid { path, .. }
+            | Error::Locked { path }
+            | Error::ReadOnly { path }
+            | Error::Permissions { path, .. }
+            | Error::Migration { path, .. } => Some(path),
+            Error::Serialize(_) | Error::Key { .. } | Error::StateDirectory { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, op, source } => {
+                write!(f, "failed to {} {}: {}", op, path.display(), source)
+            }
+            Error::Deserialize {
+                path,
+                line: Some(line),
+                column: Some(column),
+                source,
+            } => write!(
+                f,
+                "failed to deserialize {} at line {} column {}: {}",
+                path.display(),
+                line,
+                column,
+                source
+            ),
+            Error::Deserialize { path, source, .. } => {
+                write!(f, "failed to deserialize {}: {}", path.display(), source)
+            }
+            Error::Serialize(e) => write!(f, "failed to serialize state: {}", e),
+            Error::Integrity { path, reason } => {
+                write!(
+                    f,
+                    "integrity check of {} failed: {}",
+                    path.display(),
+                    reason
+                )
+            }
+            Error::Encryption { path, reason } => {
+                write!(f, "failed to decrypt {}: {}", path.display(), reason)
+            }
+            Error::Key { reason } => write!(f, "invalid encryption key: {}", reason),
+            Error::Invalid { path, reason } => {
+                write!(f, "invalid state for {}: {}", path.display(), reason)
+            }
+            Error::Locked { path } => {
+                write!(f, "{} is locked by another process", path.display())
+            }
+            Error::ReadOnly { path } => {
+                write!(f, "{} was opened read-only", path.display())
+            }
+            Error::Permissions {
+                path,
+                mode,
+                required,
+            } => write!(
+                f,
+                "{} has mode {:o}, which is more permissive than {:o}",
+                path.display(),
+                mode,
+                required
+            ),
+            Error::StateDirectory { reason } => {
+                write!(f, "failed to find a state directory: {}", reason)
+            }
+            Error::Migration {
+                path,
+                version,
+                reason,
+            } => write!(
+                f,
+                "failed to migrate {} from version {}: {}",
+                path.display(),
+                version,
+                reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Deserialize { source, .. } => Some(source.as_ref()),
+            Error::Serialize(e) => Some(e.as_ref()),
+            Error::Integrity { .. }
+            | Error::Encryption { .. }
+            | Error::Key { .. }
+            | Error::Invalid { .. }
+            | Error::Locked { .. }
+            | Error::ReadOnly { .. }
+            | Error::Permissions { .. }
+            | Error::StateDirectory { .. }
+            | Error::Migration { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_is_send_sync() {
+        fn assert_send_sync<T: std::error::Error + Send + Sync + 'static>() {}
+        assert_send_sync::<Error>();
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::io(
+            "state.json",
+            "open",
+            io::Error::new(io::ErrorKind::NotFound, "not found"),
+        );
+        assert_eq!(err.to_string(), "failed to open state.json: not found");
+        assert_eq!(err.path(), Some(Path::new("state.json")));
+
+        let source = DecodeError::new("expected value").at(2, 3);
+        let err = Error::deserialize("state.json", source);
+        assert_eq!(
+            err.to_string(),
+            "failed to deserialize state.json at line 2 column 3: expected value"
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Serialization formats a state file can be stored in.
+//!
+//! [`Json`] is always available, the others are enabled with the cargo feature
+//! of the same name.
+
+use crate::error::BoxError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+/// A serialization format for the contents of a state file.
+pub trait Format {
+    /// Serialize `value` to the bytes written to disk.
+    fn serialize<T: Serialize>(
+        &self,
+        value: &T,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Deserialize a value from the bytes read from disk.
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DecodeError>;
+}
+
+/// An error produced by a [`Format`] while deserializing.
+#[derive(Debug)]
+pub struct DecodeError {
+    pub(crate) source: BoxError,
+    pub(crate) position: Option<(usize, usize)>,
+}
+
+impl DecodeError {
+    /// Create an error without any position information.
+    pub fn new(source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        DecodeError {
+            source: source.into(),
+            position: None,
+        }
+    }
+
+    /// Attach the line and column, both starting at 1, where the error occurred.
+    pub fn at(mut self, line: usize, column: usize) -> Self {
+        self.position = Some((line, column));
+        self
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(f, "{} at line {} column {}", self.source, line, column),
+            None => self.source.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// Convert a byte offset into a line and column, both starting at 1.
+#[cfg(feature = "toml")]
+fn position(bytes: &[u8], offset: usize) -> (usize, usize) {
+    let before = &bytes[..offset.min(bytes.len())];
+    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+    let column = match before.iter().rposition(|&b| b == b'\n') {
+        Some(newline) => offset - newline,
+        None => offset + 1,
+    };
+    (line, column)
+}
+
+/// Pretty printed JSON, the default format.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Json;
+
+impl Format for Json {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(serde_json::to_vec_pretty(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DecodeError> {
+        json_from_slice(bytes)
+    }
+}
+
+/// JSON without any whitespace.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCompact;
+
+impl Format for JsonCompact {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DecodeError> {
+        json_from_slice(bytes)
+    }
+}
+
+fn json_from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DecodeError> {
+    serde_json::from_slice(bytes).map_err(|e| {
+        let (line, column) = (e.line(), e.column());
+        DecodeError::new(e).at(line, column)
+    })
+}
+
+/// Pretty printed TOML.
+///
+/// The state must serialize to a table, i.e. a struct or a map.
+#[cfg(feature = "toml")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Toml;
+
+#[cfg(feature = "toml")]
+impl Format for Toml {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(toml::to_string_pretty(value)?.into_bytes())
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DecodeError> {
+        toml::from_slice(bytes).map_err(|e| match e.span() {
+            Some(span) => {
+                let (line, column) = position(bytes, span.start);
+                DecodeError::new(e).at(line, column)
+            }
+            None => DecodeError::new(e),
+        })
+    }
+}
+
+/// YAML.
+#[cfg(feature = "yaml")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Yaml;
+
+#[cfg(feature = "yaml")]
+impl Format for Yaml {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(serde_yaml::to_string(value)?.into_bytes())
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DecodeError> {
+        serde_yaml::from_slice(bytes).map_err(|e| match e.location() {
+            Some(location) => {
+                let (line, column) = (location.line(), location.column());
+                DecodeError::new(e).at(line, column)
+            }
+            None => DecodeError::new(e),
+        })
+    }
+}
+
+/// Pretty printed [Rusty Object Notation](https://github.com/ron-rs/ron).
+#[cfg(feature = "ron")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ron;
+
+#[cfg(feature = "ron")]
+impl Format for Ron {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        let config = ron::ser::PrettyConfig::default();
+        Ok(ron::ser::to_string_pretty(value, config)?.into_bytes())
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DecodeError> {
+        ron::de::from_bytes(bytes).map_err(|e| {
+            let start = e.span.start;
+            DecodeError::new(e).at(start.line, start.col)
+        })
+    }
+}
+
+/// [MessagePack](https://msgpack.org), with structs encoded as maps so fields
+/// can be added or reordered.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Format for MessagePack {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DecodeError> {
+        rmp_serde::from_slice(bytes).map_err(DecodeError::new)
+    }
+}
+
+/// [CBOR](https://cbor.io).
+#[cfg(feature = "cbor")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Format for Cbor {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DecodeError> {
+        ciborium::from_reader(bytes).map_err(DecodeError::new)
+    }
+}
+
+/// [bincode](https://github.com/bincode-org/bincode).
+///
+/// The encoding is not self-describing, so changing the state type will
+/// usually make existing files unreadable.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Format for Bincode {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DecodeError> {
+        bincode::deserialize(bytes).map_err(DecodeError::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+    struct TestData {
+        name: String,
+        count: u32,
+        ratio: f64,
+        enabled: bool,
+        tags: Vec<String>,
+        limits: BTreeMap<String, i64>,
+        nested: Nested,
+        maybe: Option<u8>,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+    struct Nested {
+        value: i32,
+    }
+
+    fn test_data() -> TestData {
+        TestData {
+            name: "Test String".to_string(),
+            count: 42,
+            ratio: 0.5,
+            enabled: true,
+            tags: vec!["a".to_string(), "b".to_string()],
+            limits: BTreeMap::from([("max".to_string(), 10), ("min".to_string(), -10)]),
+            nested: Nested { value: -1 },
+            maybe: None,
+        }
+    }
+
+    fn round_trip(format: impl Format) {
+        let data = test_data();
+        let bytes = format.serialize(&data).unwrap();
+        let decoded: TestData = format.deserialize(&bytes).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_decode_error() {
+        use std::error::Error as _;
+
+        let error = DecodeError::new("expected value");
+        assert_eq!(error.to_string(), "expected value");
+        let error = error.at(2, 3);
+        assert_eq!(error.to_string(), "expected value at line 2 column 3");
+        assert_eq!(error.source().unwrap().to_string(), "expected value");
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        round_trip(Json);
+    }
+
+    #[test]
+    fn test_json_compact_round_trip() {
+        round_trip(JsonCompact);
+        let bytes = JsonCompact.serialize(&Nested { value: 1 }).unwrap();
+        assert_eq!(bytes, br#"{"value":1}"#);
+    }
+
+    #[test]
+    fn test_json_error_position() {
+        let err = Json
+            .deserialize::<Nested>(b"{\n  \"value\": x\n}")
+            .unwrap_err();
+        assert_eq!(err.position, Some((2, 12)));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_toml_round_trip() {
+        round_trip(Toml);
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_toml_error_position() {
+        let err = Toml.deserialize::<Nested>(b"\nvalue = x\n").unwrap_err();
+        assert_eq!(err.position, Some((2, 9)));
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_yaml_round_trip() {
+        round_trip(Yaml);
+    }
+
+    #[cfg(feature = "ron")]
+    #[test]
+    fn test_ron_round_trip() {
+        round_trip(Ron);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_round_trip() {
+        round_trip(MessagePack);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_round_trip() {
+        round_trip(Cbor);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode_round_trip() {
+        round_trip(Bincode);
+    }
+}
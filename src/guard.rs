@@ -0,0 +1,179 @@
+use crate::codec::{Codec, Encoded};
+use crate::format::Format;
+use crate::undo::{self, take_snapshot, Snapshot};
+use crate::validate::{self, Validator};
+use crate::writer::Writer;
+use crate::{Error, OnPanic};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+
+#[cfg(feature = "encryption")]
+use crate::Key;
+
+/// The parts of a file used by its write guards, borrowed from either a
+/// [`File`](crate::File) or a [`blocking::File`](crate::blocking::File).
+pub(crate) struct Parts<'a, T, F> {
+    pub(crate) path: &'a Path,
+    pub(crate) codec: &'a Codec<F>,
+    pub(crate) writer: &'a Writer,
+    pub(crate) on_panic: OnPanic,
+    pub(crate) validator: Option<&'a Validator<T>>,
+}
+
+impl<T: Serialize + DeserializeOwned, F: Format> Parts<'_, T, F> {
+    /// Change `state` with `f` and validate the result, restoring the state as
+    /// it was before if either fails or `f` panics.
+    pub(crate) fn update<R, E: From<Error>>(
+        &self,
+        state: &mut T,
+        f: impl FnOnce(&mut T) -> Result<R, E>,
+    ) -> Result<R, E> {
+        undo::run(self.codec, self.path, state, |state| -> Result<R, E> {
+            let value = f(state)?;
+            validate::check(self.validator, self.path, state)?;
+            Ok(value)
+        })
+    }
+
+    /// Switch to encrypting with `key`, returning `state` encoded with it to
+    /// be written, and the previous key to put back if that fails.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn replace_key(&self, state: &T, key: Key) -> Result<(Encoded, Option<Key>), Error> {
+        let old = self.set_key(Some(key));
+        match self.codec.encode(state) {
+            Ok(mut encoded) => {
+                // the state hasn't changed but the file must be rewritten anyway
+                encoded.force = true;
+                Ok((encoded, old))
+            }
+            Err(e) => {
+                self.set_key(old);
+                Err(e)
+            }
+        }
+    }
+
+    /// Replace the key used to encrypt the file, returning the previous one.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn set_key(&self, key: Option<Key>) -> Option<Key> {
+        let mut current = self.codec.key.write().unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut current, key)
+    }
+}
+
+/// How far a write guard has got, whichever kind of lock it holds.
+#[derive(Default)]
+pub(crate) struct Progress {
+    finished: bool,
+    dirty: bool,
+    /// The state before the first change, for rolling back on panic or
+    /// rejection by the validator.
+    snapshot: Option<Snapshot>,
+}
+
+impl Progress {
+    /// Note that `state` is about to be changed through the guard, taking a
+    /// snapshot first if the change might have to be rolled back.
+    pub(crate) fn change<T: Serialize, F: Format>(&mut self, parts: &Parts<'_, T, F>, state: &T) {
+        if !self.dirty {
+            self.dirty = true;
+            if parts.on_panic == OnPanic::Rollback || parts.validator.is_some() {
+                self.snapshot = take_snapshot(parts.codec, state);
+            }
+        }
+    }
+
+    /// Note that `state` was changed and validated by [`Parts::update`].
+    pub(crate) fn changed(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Release the guard without writing.
+    pub(crate) fn abandon(&mut self) {
+        self.finished = true;
+    }
+
+    /// Validate and encode `state` to be written by a committed guard.
+    pub(crate) fn commit<T: Serialize + DeserializeOwned, F: Format>(
+        &mut self,
+        parts: &Parts<'_, T, F>,
+        state: &mut T,
+    ) -> Result<Encoded, Error> {
+        self.finished = true;
+        self.validate(parts, state)?;
+        parts.codec.encode(state)
+    }
+
+    /// Check `state` with the file's validator, rolling back the changes made
+    /// through the guard if it's rejected.
+    fn validate<T: Serialize + DeserializeOwned, F: Format>(
+        &mut self,
+        parts: &Parts<'_, T, F>,
+        state: &mut T,
+    ) -> Result<(), Error> {
+        let result = validate::check(parts.validator, parts.path, state);
+        if result.is_err() {
+            self.roll_back(parts, state);
+        }
+        result
+    }
+
+    fn roll_back<T: DeserializeOwned, F: Format>(
+        &mut self,
+        parts: &Parts<'_, T, F>,
+        state: &mut T,
+    ) {
+        if let Some(snapshot) = self.snapshot.take() {
+            snapshot.restore(parts.codec, parts.path, state);
+            self.dirty = false;
+        }
+    }
+
+    /// Handle `state` as the guard is dropped, handing it to the writer if it
+    /// should be written. Returns whether the state was left changed.
+    pub(crate) fn release<T: Serialize + DeserializeOwned, F: Format>(
+        &mut self,
+        parts: &Parts<'_, T, F>,
+        state: &mut T,
+    ) -> bool {
+        // the changes may have been interrupted part way through
+        let panicking = self.dirty && std::thread::panicking();
+        if panicking && parts.on_panic == OnPanic::Rollback {
+            self.roll_back(parts, state);
+        }
+
+        // validate whatever is about to be written, even by a panicking
+        // thread; the error is reported by the next flush
+        let mut rejected = false;
+        let writing = !panicking || parts.on_panic == OnPanic::Write;
+        if self.dirty && !self.finished && writing {
+            if let Err(e) = self.validate(parts, state) {
+                log::error!("Not writing rejected state: {}", e);
+                parts.writer.report(e);
+                rejected = true;
+            }
+        }
+
+        if self.finished || rejected {
+            return self.dirty;
+        }
+
+        if !self.dirty {
+            parts.writer.skip();
+            return false;
+        }
+
+        if panicking && parts.on_panic != OnPanic::Write {
+            log::warn!("Not writing state changed by a panicking thread");
+            return true;
+        }
+
+        match parts.codec.encode(state) {
+            // hand off to the writer thread, see File::flush for the result
+            Ok(encoded) => parts.writer.save(encoded),
+            Err(e) => log::error!("Failed to serialize state: {}", e),
+        }
+        true
+    }
+}
@@ -0,0 +1,167 @@
+//! Checksums stored alongside the state to detect corruption.
+//!
+//! A protected file starts with a header made up of [`MAGIC`], a byte
+//! identifying the algorithm and the digest of the rest of the file.
+
+use crate::Error;
+use std::path::Path;
+
+/// Marks the start of a file protected by a checksum.
+const MAGIC: &[u8; 4] = b"SFCK";
+
+/// An algorithm used to check the integrity of a state file.
+///
+/// Files are verified when they're opened if they have a checksum, whether or
+/// not one is configured, and a mismatch is reported as
+/// [`Error::Integrity`](crate::Error::Integrity).
+///
+/// Once an algorithm is configured, files without a checksum are rejected the
+/// same way: a damaged or stripped header can't be told apart from a file
+/// that never had one, so accepting them would skip the check entirely. An
+/// empty file is still treated as new. Existing files written before
+/// integrity was enabled must be handled with
+/// [`FileBuilder::recovery`](crate::FileBuilder::recovery).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Integrity {
+    /// CRC-32C (Castagnoli), a fast check against accidental corruption.
+    #[cfg(feature = "crc32c")]
+    Crc32c,
+    /// A BLAKE3 hash, which also detects deliberate tampering of the payload
+    /// as long as the header is trusted.
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl Integrity {
+    fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "crc32c")]
+            Integrity::Crc32c => 1,
+            #[cfg(feature = "blake3")]
+            Integrity::Blake3 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            #[cfg(feature = "crc32c")]
+            1 => Some(Integrity::Crc32c),
+            #[cfg(feature = "blake3")]
+            2 => Some(Integrity::Blake3),
+            _ => None,
+        }
+    }
+
+    fn digest(self, payload: &[u8]) -> Vec<u8> {
+        // unused when no algorithm is enabled
+        let _ = payload;
+        match self {
+            #[cfg(feature = "crc32c")]
+            Integrity::Crc32c => crc32c::crc32c(payload).to_le_bytes().to_vec(),
+            #[cfg(feature = "blake3")]
+            Integrity::Blake3 => blake3::hash(payload).as_bytes().to_vec(),
+        }
+    }
+
+    /// Prefix `payload` with a header containing its digest.
+    pub(crate) fn seal(self, payload: Vec<u8>) -> Vec<u8> {
+        let digest = self.digest(&payload);
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + digest.len() + payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.id());
+        bytes.extend_from_slice(&digest);
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+}
+
+/// Verify the checksum of the file at `path` if it has one, returning the
+/// payload. If `required` is set a file without a checksum is rejected.
+pub(crate) fn open<'a>(path: &Path, contents: &'a [u8], required: bool) -> Result<&'a [u8], Error> {
+    let error = |reason: &str| Error::Integrity {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+    };
+
+    let rest = match contents.strip_prefix(MAGIC) {
+        Some(rest) => rest,
+        None if required => return Err(error("missing checksum")),
+        None => return Ok(contents),
+    };
+
+    let (&id, rest) = rest
+        .split_first()
+        .ok_or_else(|| error("truncated header"))?;
+    let integrity = Integrity::from_id(id).ok_or_else(|| error("unsupported checksum"))?;
+    let len = integrity.digest(&[]).len();
+    if rest.len() < len {
+        return Err(error("truncated header"));
+    }
+
+    let (digest, payload) = rest.split_at(len);
+    if integrity.digest(payload) != digest {
+        return Err(error("checksum mismatch"));
+    }
+
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_unprotected() {
+        let path = Path::new("state.json");
+        assert_eq!(open(path, b"{}", false).unwrap(), b"{}");
+        assert!(matches!(
+            open(path, b"{}", true),
+            Err(Error::Integrity { .. })
+        ));
+    }
+
+    #[test]
+    fn test_open_unsupported() {
+        let path = Path::new("state.json");
+        assert!(matches!(
+            open(path, b"SFCK\xff{}", false),
+            Err(Error::Integrity { .. })
+        ));
+        assert!(matches!(
+            open(path, b"SFCK", false),
+            Err(Error::Integrity { .. })
+        ));
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn test_crc32c() {
+        let path = Path::new("state.json");
+        let mut bytes = Integrity::Crc32c.seal(b"{\"a\":1}".to_vec());
+        assert_eq!(bytes.len(), 4 + 1 + 4 + 7);
+        assert_eq!(open(path, &bytes, true).unwrap(), b"{\"a\":1}");
+
+        // flip a bit in the payload
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            open(path, &bytes, true),
+            Err(Error::Integrity { .. })
+        ));
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_blake3() {
+        let path = Path::new("state.json");
+        let mut bytes = Integrity::Blake3.seal(b"{\"a\":1}".to_vec());
+        assert_eq!(bytes.len(), 4 + 1 + 32 + 7);
+        assert_eq!(open(path, &bytes, true).unwrap(), b"{\"a\":1}");
+
+        bytes.truncate(20);
+        assert!(matches!(
+            open(path, &bytes, true),
+            Err(Error::Integrity { .. })
+        ));
+    }
+}
@@ -1,54 +1,140 @@
+#[cfg(feature = "async")]
+use async_lock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(feature = "async")]
 use serde::de::DeserializeOwned;
+#[cfg(feature = "async")]
 use serde::Serialize;
-use std::error::Error;
-use std::fs::OpenOptions;
-use std::io::prelude::*;
+#[cfg(feature = "async")]
 use std::path::{Path, PathBuf};
-use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(feature = "async")]
+use std::sync::Arc;
+#[cfg(feature = "async")]
+use std::time::Duration;
 
-pub struct WriteGuard<'a, T: Serialize + DeserializeOwned + Default> {
+mod backup;
+pub mod blocking;
+mod builder;
+mod codec;
+mod compression;
+mod disk;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod error;
+pub mod format;
+mod guard;
+mod integrity;
+mod lock;
+mod migrate;
+mod permissions;
+#[cfg(feature = "async")]
+mod read_only;
+mod recovery;
+mod state_dir;
+#[cfg(feature = "async")]
+mod subscription;
+mod undo;
+mod validate;
+#[cfg(feature = "watch")]
+mod watch;
+mod writer;
+
+pub use backup::{Backup, Backups};
+pub use builder::FileBuilder;
+pub use compression::Compression;
+pub use disk::Fsync;
+#[cfg(feature = "encryption")]
+pub use encryption::Key;
+pub use error::Error;
+pub use format::{Format, Json};
+pub use integrity::Integrity;
+pub use lock::Lock;
+pub use migrate::Migrations;
+pub use permissions::Permissions;
+#[cfg(feature = "async")]
+pub use read_only::ReadOnlyFile;
+pub use recovery::{Recovered, Recovery};
+pub use state_dir::state_directory;
+#[cfg(feature = "async")]
+use subscription::Changes;
+#[cfg(feature = "async")]
+pub use subscription::Subscription;
+pub use undo::OnPanic;
+pub use validate::Validate;
+pub use writer::Stats;
+
+#[cfg(feature = "async")]
+use codec::Codec;
+#[cfg(feature = "async")]
+use guard::{Parts, Progress};
+#[cfg(feature = "async")]
+use validate::Validator;
+#[cfg(feature = "async")]
+use writer::Writer;
+
+/// Exclusive write access to a state file.
+///
+/// Changes are persisted to disk when the guard is dropped. Use
+/// [`WriteGuard::commit`] to find out whether that succeeded, or
+/// [`WriteGuard::abandon`] to release the guard without writing.
+///
+/// A guard that was never mutably dereferenced, or whose changes leave the
+/// serialized state identical to what is on disk, doesn't write anything.
+///
+/// If the guard is dropped while its thread panics the changes may be
+/// incomplete, so by default they are rolled back instead of written, see
+/// [`OnPanic`].
+#[cfg(feature = "async")]
+pub struct WriteGuard<'a, T: Serialize + DeserializeOwned + Default, F: Format = Json> {
     guard: RwLockWriteGuard<'a, T>,
-    path: PathBuf,
+    file: &'a File<T, F>,
+    progress: Progress,
 }
 
-impl<'a, T: Serialize + DeserializeOwned + Default> Drop for WriteGuard<'a, T> {
-    fn drop(&mut self) {
-        // convert data structure to pretty JSON string
-        let json = match serde_json::to_string_pretty(&*self.guard) {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!("Failed to serialize JSON: {}", e);
-                return;
-            }
-        };
+#[cfg(feature = "async")]
+impl<'a, T: Serialize + DeserializeOwned + Default, F: Format> WriteGuard<'a, T, F> {
+    /// Write the state to disk and release the lock, returning any error that
+    /// occurred.
+    ///
+    /// This blocks the current thread until the data is on disk, see
+    /// [`WriteGuard::commit_async`] for use within async code.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let encoded = self.progress.commit(&self.file.parts(), &mut self.guard)?;
+        self.file.writer.save_blocking(encoded)
+    }
 
-        // open the state file
-        let path = self.path.clone();
-        let mut file = match OpenOptions::new().write(true).create(true).open(&path) {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!("Failed to open file {}: {}", path.display(), e);
-                return;
-            }
-        };
+    /// Write the state to disk and release the lock, returning any error that
+    /// occurred.
+    ///
+    /// The file is written by a background thread so the runtime isn't stalled
+    /// by slow storage.
+    pub async fn commit_async(mut self) -> Result<(), Error> {
+        let encoded = self.progress.commit(&self.file.parts(), &mut self.guard)?;
+        self.file.writer.save_async(encoded).await
+    }
 
-        // write to disk
-        if let Err(e) = file.write_all(json.as_bytes()) {
-            log::error!("Failed to write to file {}: {}", path.display(), e);
-            return;
-        }
+    /// Release the lock without writing to disk.
+    ///
+    /// Any changes made through this guard remain in memory and will be
+    /// written by the next guard that modifies the state or is committed.
+    pub fn abandon(mut self) {
+        self.progress.abandon();
+    }
+}
 
-        // ensure data makes it to disk
-        if let Err(e) = file.flush() {
-            log::error!("Failed to flush file {}: {}", path.display(), e);
-            return;
+#[cfg(feature = "async")]
+impl<'a, T: Serialize + DeserializeOwned + Default, F: Format> Drop for WriteGuard<'a, T, F> {
+    fn drop(&mut self) {
+        if self.progress.release(&self.file.parts(), &mut self.guard) {
+            // subscribers have to wait for the lock so they see the change
+            self.file.changed.notify();
         }
-
-        log::info!("Data successfully written to file {}", path.display())
     }
 }
 
-impl<'a, T: Serialize + DeserializeOwned + Default> std::ops::Deref for WriteGuard<'a, T> {
+#[cfg(feature = "async")]
+impl<'a, T: Serialize + DeserializeOwned + Default, F: Format> std::ops::Deref
+    for WriteGuard<'a, T, F>
+{
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -56,16 +142,28 @@ impl<'a, T: Serialize + DeserializeOwned + Default> std::ops::Deref for WriteGua
     }
 }
 
-impl<'a, T: Serialize + DeserializeOwned + Default> std::ops::DerefMut for WriteGuard<'a, T> {
+#[cfg(feature = "async")]
+impl<'a, T: Serialize + DeserializeOwned + Default, F: Format> std::ops::DerefMut
+    for WriteGuard<'a, T, F>
+{
     fn deref_mut(&mut self) -> &mut Self::Target {
+        self.progress.change(&self.file.parts(), &self.guard);
         &mut self.guard
     }
 }
 
 /// A state file.
 ///
-/// This provides strongly typed access to a JSON file wrapped in a `RwLock`
-/// that writes to disk once write access is dropped.
+/// This provides strongly typed access to a file wrapped in a `RwLock` that
+/// writes to disk once write access is dropped. The file is stored as pretty
+/// JSON unless another [`Format`] is given.
+///
+/// Writes are performed by a background thread so dropping a [`WriteGuard`]
+/// never blocks. Use [`File::flush`] to wait for them to reach the disk.
+/// Dropping the `File` waits for any pending writes to complete.
+///
+/// By default the file is locked so that only one process can have it open,
+/// see [`Lock`] for the alternatives.
 ///
 /// ```rust
 /// use statefile::File;
@@ -88,39 +186,88 @@ impl<'a, T: Serialize + DeserializeOwned + Default> std::ops::DerefMut for Write
 ///     write_guard.foo = "".to_string();
 ///     write_guard.bar = 10;
 ///     drop(write_guard); // write state by explicitly dropping
+///
+///     state.flush().await.unwrap(); // wait for the write to complete
+/// #   drop(state);
+/// #   std::fs::remove_file("mystate.json").unwrap();
+/// #   std::fs::remove_file("mystate.json.lock").unwrap();
 /// }
 /// ```
 ///
-pub struct File<T: Serialize + DeserializeOwned + Default> {
-    data: RwLock<T>,
+#[cfg(feature = "async")]
+pub struct File<T: Serialize + DeserializeOwned + Default, F: Format = Json> {
+    data: Arc<RwLock<T>>,
     path: PathBuf,
+    codec: Arc<Codec<F>>,
+    /// Notifies subscribers of changes to the state.
+    changed: Arc<Changes>,
+    /// Stopped before the writer, which can't finish while it's reloading.
+    #[cfg(feature = "watch")]
+    watcher: Option<watch::Watcher>,
+    writer: Writer,
+    recovered: Option<Recovered>,
+    on_panic: OnPanic,
+    validator: Option<Validator<T>>,
+    /// Held until the file is dropped, after the writer has finished.
+    _lock: Option<std::fs::File>,
 }
 
-impl<T: Serialize + DeserializeOwned + Default> File<T> {
+#[cfg(feature = "async")]
+impl<T: Serialize + DeserializeOwned + Default, F: Format + Default> File<T, F> {
     /// Create a new state file at the given path
-    pub async fn new(path: impl AsRef<Path> + Copy) -> Result<Self, Box<dyn Error>> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)?;
-
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-
-        let data = if contents.is_empty() {
-            T::default()
-        } else {
-            serde_json::from_str(&contents)?
-        };
+    ///
+    /// The file is exclusively locked against other processes, failing if one
+    /// of them already holds the lock. Use [`File::builder`] to configure this
+    /// and the other options.
+    pub async fn new(path: impl AsRef<Path>) -> Result<Self, Error>
+    where
+        T: Send + 'static,
+        F: Send + 'static,
+    {
+        Self::builder(path).build().await
+    }
 
-        let data = RwLock::new(data);
+    /// Create a new state file with the given name in the service's state
+    /// directory, see [`state_directory`].
+    ///
+    /// ```no_run
+    /// # async fn open() -> Result<(), statefile::Error> {
+    /// let state = statefile::File::<u32>::in_state_directory("state.json").await?;
+    /// println!("state is stored in {}", state.path().display());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn in_state_directory(name: impl AsRef<Path>) -> Result<Self, Error>
+    where
+        T: Send + 'static,
+        F: Send + 'static,
+    {
+        let path = disk::unblock(Path::new(""), state_directory).await?;
+        Self::builder(path.join(name)).build().await
+    }
 
-        let path = path.as_ref().to_path_buf();
+    /// Open an existing state file for reading only.
+    ///
+    /// Unlike [`File::new`], this fails if the file doesn't exist. The file is
+    /// never created, written or locked, so it can be read while another
+    /// process owns it. Use [`FileBuilder::build_read_only`] to read a file
+    /// with migrations, integrity, compression or encryption.
+    pub async fn open_read_only(path: impl AsRef<Path>) -> Result<ReadOnlyFile<T, F>, Error>
+    where
+        T: Send + 'static,
+        F: Send + 'static,
+    {
+        Self::builder(path).build_read_only().await
+    }
 
-        Ok(File { data, path })
+    /// Configure how a state file at the given path is opened.
+    pub fn builder(path: impl AsRef<Path>) -> FileBuilder<T, F> {
+        FileBuilder::new(path.as_ref().to_path_buf())
     }
+}
 
+#[cfg(feature = "async")]
+impl<T: Serialize + DeserializeOwned + Default, F: Format> File<T, F> {
     /// Locks this state file with shared read access, causing the current task
     /// to yield until the lock has been acquired.
     pub async fn read(&self) -> RwLockReadGuard<'_, T> {
@@ -129,19 +276,174 @@ impl<T: Serialize + DeserializeOwned + Default> File<T> {
 
     /// Locks this state file with exclusive write access, causing the current
     /// task to yield until the lock has been acquired.
-    pub async fn write(&self) -> WriteGuard<'_, T> {
+    pub async fn write(&self) -> WriteGuard<'_, T, F> {
         WriteGuard {
             guard: self.data.write().await,
-            path: self.path.clone(),
+            file: self,
+            progress: Progress::default(),
+        }
+    }
+
+    /// Change the state with `f`, writing it to disk if `f` succeeds.
+    ///
+    /// If `f` returns an error or panics, or the new state is rejected by the
+    /// validator set with [`FileBuilder::validate`], the state is restored to
+    /// how it was before and nothing is written, so a failed change is never
+    /// half applied. Otherwise the result of `f` is returned once the state is on
+    /// disk, or the error from writing it. The write lock is held throughout.
+    ///
+    /// The state is restored from a copy serialized before calling `f`, so
+    /// any fields skipped by serde are reset to their defaults.
+    ///
+    /// ```rust
+    /// # use statefile::File;
+    /// # use std::error::Error;
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let file = File::<Vec<u32>>::new("update.json").await.unwrap();
+    /// let result: Result<usize, Box<dyn Error>> = file
+    ///     .update(|list| {
+    ///         list.push(1);
+    ///         if list.len() > 10 {
+    ///             // the push is undone
+    ///             return Err("the list is full".into());
+    ///         }
+    ///         Ok(list.len())
+    ///     })
+    ///     .await;
+    /// assert_eq!(result.unwrap(), 1);
+    /// # drop(file);
+    /// # std::fs::remove_file("update.json").unwrap();
+    /// # std::fs::remove_file("update.json.lock").unwrap();
+    /// # }
+    /// ```
+    pub async fn update<R, E: From<Error>>(
+        &self,
+        f: impl FnOnce(&mut T) -> Result<R, E>,
+    ) -> Result<R, E> {
+        let mut write_guard = self.write().await;
+        // bypass the guard so it isn't written if `f` panics
+        let value = self.parts().update(&mut write_guard.guard, f)?;
+        write_guard.progress.changed();
+        write_guard.commit_async().await?;
+        Ok(value)
+    }
+
+    /// Get notified whenever the state changes, see [`Subscription`].
+    pub fn subscribe(&self) -> Subscription {
+        self.changed.subscribe()
+    }
+
+    /// Wait for all pending writes to reach the disk.
+    ///
+    /// Returns the first error from a write made by dropping a [`WriteGuard`]
+    /// since the last flush.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.writer.flush().await
+    }
+
+    /// Limit how often dropped [`WriteGuard`]s are written to disk.
+    ///
+    /// With an interval set, changes are written at most once per interval and
+    /// intermediate states are skipped. This reduces wear on flash storage when
+    /// state is updated frequently. Committed guards, [`File::flush`] and
+    /// dropping the `File` still write immediately. Pass `None` to write every
+    /// dropped guard, which is the default.
+    pub fn set_debounce(&self, interval: Option<Duration>) {
+        self.writer.debounce(interval);
+    }
+
+    /// Counters describing the writes made to this file so far.
+    pub fn stats(&self) -> Stats {
+        self.writer.stats()
+    }
+
+    /// List the backups of this file, newest first.
+    ///
+    /// Backups are only made if configured with [`FileBuilder::backups`].
+    pub async fn backups(&self) -> Result<Vec<Backup>, Error> {
+        let path = self.path.clone();
+        disk::unblock(&self.path, move || backup::list(&path)).await
+    }
+
+    /// Read the state stored in a backup.
+    pub async fn load_backup(&self, backup: &Backup) -> Result<T, Error> {
+        let path = backup.path().to_path_buf();
+        let contents = disk::unblock(&path, {
+            let path = path.clone();
+            move || std::fs::read(&path).map_err(|e| Error::io(&path, "read", e))
+        })
+        .await?;
+        Ok(self.codec.decode(&path, &contents)?.state)
+    }
+
+    /// Re-encrypt the file under a new key, which is used from now on.
+    #[cfg(feature = "encryption")]
+    pub async fn rotate_key(&self, key: Key) -> Result<(), Error> {
+        // hold the lock so no other writes are encrypted with the old key
+        let guard = self.data.write().await;
+        let (encoded, old) = self.parts().replace_key(&guard, key)?;
+        let result = self.writer.save_async(encoded).await;
+
+        if result.is_err() {
+            // the file is still encrypted with the old key
+            self.parts().set_key(old);
+        }
+        result
+    }
+
+    /// Replace the state with the one stored in a backup.
+    ///
+    /// The current state is backed up in turn, so restoring can be undone.
+    pub async fn restore(&self, backup: &Backup) -> Result<(), Error> {
+        let state = self.load_backup(backup).await?;
+        let mut write_guard = self.write().await;
+        *write_guard = state;
+        write_guard.commit_async().await
+    }
+
+    /// The action taken when opening the file to recover from it being
+    /// corrupt, see [`FileBuilder::recovery`].
+    pub fn recovered(&self) -> Option<&Recovered> {
+        self.recovered.as_ref()
+    }
+
+    /// Take the error from the most recent failed attempt to reload the file
+    /// after it was changed by another process, see [`FileBuilder::watch`].
+    #[cfg(feature = "watch")]
+    pub fn reload_error(&self) -> Option<Error> {
+        self.watcher.as_ref()?.take_error()
+    }
+
+    /// The path of the state file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn parts(&self) -> Parts<'_, T, F> {
+        Parts {
+            path: &self.path,
+            codec: &self.codec,
+            writer: &self.writer,
+            on_panic: self.on_panic,
+            validator: self.validator.as_ref(),
         }
     }
 }
 
-#[cfg(test)]
+#[cfg(feature = "async")]
+impl<T: Serialize + DeserializeOwned + Default, F: Format> Drop for File<T, F> {
+    fn drop(&mut self) {
+        self.changed.close();
+    }
+}
+
+#[cfg(all(test, feature = "async"))]
 mod tests {
     use super::*;
     use serde::{Deserialize, Serialize};
     use std::fs;
+    use std::io::prelude::*;
 
     #[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
     struct TestData {
@@ -149,59 +451,1110 @@ mod tests {
         field2: u32,
     }
 
-    #[tokio::test]
-    async fn test_file_create_and_write() {
-        let test_path = "test_file_create_and_write.json";
-        let file = File::<TestData>::new(test_path).await.unwrap();
+    /// Run an async test once on each runtime enabled by cargo feature.
+    pub(crate) fn run<Fut: std::future::Future<Output = ()>>(test: impl Fn() -> Fut) {
+        #[cfg(feature = "tokio")]
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(test());
+        #[cfg(feature = "async-std")]
+        async_std::task::block_on(test());
+        #[cfg(feature = "smol")]
+        smol::block_on(test());
+    }
+
+    #[test]
+    fn test_file_create_and_write() {
+        run(|| async {
+            let test_path = "test_file_create_and_write.json";
+            let file = File::<TestData>::new(test_path).await.unwrap();
 
-        let mut write_guard = file.write().await;
-        write_guard.field1 = String::from("Test String");
-        write_guard.field2 = 42;
-        drop(write_guard); // Forces the Drop trait to be called, data should be written to the file
+            let mut write_guard = file.write().await;
+            write_guard.field1 = String::from("Test String");
+            write_guard.field2 = 42;
+            drop(write_guard); // Forces the Drop trait to be called, data should be written to the file
+            file.flush().await.unwrap();
 
-        let mut file_content = String::new();
-        std::fs::File::open(test_path)
-            .unwrap()
-            .read_to_string(&mut file_content)
-            .unwrap();
+            let mut file_content = String::new();
+            std::fs::File::open(test_path)
+                .unwrap()
+                .read_to_string(&mut file_content)
+                .unwrap();
 
-        assert_eq!(
-            file_content,
-            r#"{
+            assert_eq!(
+                file_content,
+                r#"{
   "field1": "Test String",
   "field2": 42
 }"#
-        );
+            );
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_read() {
+        run(|| async {
+            let test_path = "test_file_read.json";
+            std::fs::write(test_path, r#"{"field1":"Test String","field2":42}"#).unwrap(); // Write initial data
+
+            let file = File::<TestData>::new(test_path).await.unwrap();
+            let read_guard = file.read().await;
+
+            assert_eq!(read_guard.field1, "Test String");
+            assert_eq!(read_guard.field2, 42);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_read_default() {
+        run(|| async {
+            let test_path = "test_file_read_default.json";
+            std::fs::write(test_path, "").unwrap(); // Write empty file
+
+            let file = File::<TestData>::new(test_path).await.unwrap();
+            let read_guard = file.read().await;
+
+            // Check default values
+            assert_eq!(read_guard.field1, "");
+            assert_eq!(read_guard.field2, 0);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_write_atomic() {
+        run(|| async {
+            let test_path = "test_file_write_atomic.json";
+            std::fs::write(
+                test_path,
+                r#"{"field1":"A much longer test string","field2":42}"#,
+            )
+            .unwrap();
+
+            let file = File::<TestData>::new(test_path).await.unwrap();
+            let mut write_guard = file.write().await;
+            write_guard.field1 = String::from("Short");
+            drop(write_guard);
+            file.flush().await.unwrap();
+
+            // the shorter contents fully replace the old ones
+            let file_content = fs::read_to_string(test_path).unwrap();
+            let data: TestData = serde_json::from_str(&file_content).unwrap();
+            assert_eq!(data.field1, "Short");
+            assert_eq!(data.field2, 42);
+
+            // no temporary file is left behind
+            assert!(!Path::new("test_file_write_atomic.json.tmp").exists());
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_write_guard_commit() {
+        run(|| async {
+            let test_path = "test_write_guard_commit.json";
+            let file = File::<TestData>::new(test_path).await.unwrap();
+
+            let mut write_guard = file.write().await;
+            write_guard.field2 = 1;
+            write_guard.commit().unwrap();
+
+            let mut write_guard = file.write().await;
+            write_guard.field2 = 2;
+            write_guard.commit_async().await.unwrap();
+
+            let file_content = fs::read_to_string(test_path).unwrap();
+            let data: TestData = serde_json::from_str(&file_content).unwrap();
+            assert_eq!(data.field2, 2);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_write_guard_commit_error() {
+        run(|| async {
+            let test_dir = "test_write_guard_commit_error";
+            fs::create_dir(test_dir).unwrap();
+            let file = File::<TestData>::new("test_write_guard_commit_error/state.json")
+                .await
+                .unwrap();
+            fs::remove_dir_all(test_dir).unwrap();
+
+            // the parent directory no longer exists
+            let result = file.write().await.commit();
+            assert!(matches!(result, Err(Error::Io { op: "create", .. })));
+
+            // errors from dropped guards are reported by flush
+            file.write().await.field2 = 1;
+            assert!(file.flush().await.is_err());
+            assert!(file.flush().await.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_write_guard_abandon() {
+        run(|| async {
+            let test_path = "test_write_guard_abandon.json";
+            let file = File::<TestData>::new(test_path).await.unwrap();
+
+            let mut write_guard = file.write().await;
+            write_guard.field2 = 42;
+            write_guard.abandon();
+
+            // memory keeps the change but nothing was written
+            assert_eq!(file.read().await.field2, 42);
+            assert_eq!(fs::read_to_string(test_path).unwrap(), "");
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_read_invalid() {
+        run(|| async {
+            let test_path = "test_file_read_invalid.json";
+            std::fs::write(test_path, "{\n  \"field1\": 42\n}").unwrap();
+
+            let result = File::<TestData>::new(test_path).await;
+            match result {
+                Err(Error::Deserialize {
+                    path, line, column, ..
+                }) => {
+                    assert_eq!(path, Path::new(test_path));
+                    assert_eq!((line, column), (Some(2), Some(14)));
+                }
+                _ => panic!("expected deserialize error"),
+            }
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_compact_format() {
+        run(|| async {
+            let test_path = "test_file_compact_format.json";
+            let file = File::<TestData, format::JsonCompact>::new(test_path)
+                .await
+                .unwrap();
+
+            let mut write_guard = file.write().await;
+            write_guard.field1 = String::from("Test String");
+            write_guard.field2 = 42;
+            drop(write_guard);
+            file.flush().await.unwrap();
+
+            assert_eq!(
+                fs::read_to_string(test_path).unwrap(),
+                r#"{"field1":"Test String","field2":42}"#
+            );
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_drop_flushes() {
+        run(|| async {
+            let test_path = "test_file_drop_flushes.json";
+            let file = File::<TestData>::new(test_path).await.unwrap();
+
+            let mut write_guard = file.write().await;
+            write_guard.field2 = 42;
+            drop(write_guard);
+            drop(file); // waits for the pending write
+
+            let file_content = fs::read_to_string(test_path).unwrap();
+            let data: TestData = serde_json::from_str(&file_content).unwrap();
+            assert_eq!(data.field2, 42);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_debounce() {
+        run(|| async {
+            let test_path = "test_file_debounce.json";
+            let file = File::<TestData>::new(test_path).await.unwrap();
+            file.set_debounce(Some(Duration::from_secs(3600)));
+
+            file.write().await.field2 = 1;
+            file.flush().await.unwrap();
+
+            // within the interval changes are held back
+            file.write().await.field2 = 2;
+            file.write().await.field2 = 3;
+            let data: TestData =
+                serde_json::from_str(&fs::read_to_string(test_path).unwrap()).unwrap();
+            assert_eq!(data.field2, 1);
+
+            // until the file is flushed
+            file.flush().await.unwrap();
+            let data: TestData =
+                serde_json::from_str(&fs::read_to_string(test_path).unwrap()).unwrap();
+            assert_eq!(data.field2, 3);
+
+            // or dropped
+            file.write().await.field2 = 4;
+            assert_eq!(file.stats().coalesced, 1);
+            drop(file);
+            let data: TestData =
+                serde_json::from_str(&fs::read_to_string(test_path).unwrap()).unwrap();
+            assert_eq!(data.field2, 4);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_skip_unchanged() {
+        run(|| async {
+            let test_path = "test_file_skip_unchanged.json";
+            std::fs::write(test_path, "{\n  \"field1\": \"\",\n  \"field2\": 1\n}").unwrap();
+            let file = File::<TestData>::new(test_path).await.unwrap();
+
+            // not mutably dereferenced
+            let write_guard = file.write().await;
+            assert_eq!(write_guard.field2, 1);
+            drop(write_guard);
 
-        let _ = fs::remove_file(test_path); // Clean up test file
+            // identical to what was loaded
+            file.write().await.field2 = 1;
+            file.flush().await.unwrap();
+            assert_eq!(
+                file.stats(),
+                Stats {
+                    writes: 0,
+                    skipped: 2,
+                    coalesced: 0,
+                    reloads: 0
+                }
+            );
+
+            file.write().await.field2 = 2;
+            file.flush().await.unwrap();
+            file.write().await.field2 = 2;
+            file.flush().await.unwrap();
+            assert_eq!(
+                file.stats(),
+                Stats {
+                    writes: 1,
+                    skipped: 3,
+                    coalesced: 0,
+                    reloads: 0
+                }
+            );
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_builder_options() {
+        run(|| async {
+            let test_path = PathBuf::from("test_file_builder_options.json");
+
+            let result = File::<TestData>::builder(&test_path)
+                .create(false)
+                .build()
+                .await;
+            assert!(matches!(result, Err(Error::Io { op: "open", .. })));
+            assert!(!test_path.exists());
+            assert!(!Path::new("test_file_builder_options.json.lock").exists());
+
+            // a missing directory is reported against the state file, not the lock
+            let result = File::<TestData>::builder("test_file_builder_options/state.json")
+                .create(false)
+                .build()
+                .await;
+            assert!(matches!(
+                result,
+                Err(Error::Io { path, op: "open", .. })
+                    if path == Path::new("test_file_builder_options/state.json")
+            ));
+
+            let file = File::<TestData>::builder(&test_path)
+                .persist_defaults(true)
+                .compact()
+                .fsync(Fsync::Never)
+                .debounce(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(
+                fs::read_to_string(&test_path).unwrap(),
+                r#"{"field1":"","field2":0}"#
+            );
+
+            // only the last of several dropped guards is written
+            file.write().await.field2 = 1;
+            file.write().await.field2 = 2;
+            file.flush().await.unwrap();
+            assert_eq!(file.stats().coalesced, 1);
+            drop(file);
+
+            // owned paths are accepted too
+            let file = File::<TestData>::new(test_path.clone()).await.unwrap();
+            assert_eq!(file.read().await.field2, 2);
+            drop(file);
+
+            let _ = fs::remove_file(&test_path); // Clean up test file
+            let _ = fs::remove_file("test_file_builder_options.json.lock");
+
+            // options set before the format is changed are kept
+            let test_path = Path::new("test_file_builder_options_compact.json");
+            fs::write(test_path, "{").unwrap();
+            let builder = File::<TestData>::builder(test_path)
+                .create(false)
+                .persist_defaults(true)
+                .lock(Lock::None)
+                .migrations(Migrations::new().step(Ok::<_, String>))
+                .backups(Backups::keep(1))
+                .recovery(Recovery::Quarantine)
+                .validate(|state| match state.field2 {
+                    3 => Err("field2 is 3".to_string()),
+                    _ => Ok(()),
+                });
+            #[cfg(feature = "watch")]
+            let builder = builder.watch();
+            let file = builder.compact().build().await.unwrap();
+
+            let quarantined = match file.recovered() {
+                Some(Recovered::Quarantined { path, .. }) => path.clone().unwrap(),
+                _ => panic!("expected quarantine"),
+            };
+            assert_eq!(
+                fs::read_to_string(test_path).unwrap(),
+                r#"{"version":1,"state":{"field1":"","field2":0}}"#
+            );
+            assert!(!Path::new("test_file_builder_options_compact.json.lock").exists());
+
+            let mut write_guard = file.write().await;
+            write_guard.field2 = 3;
+            assert!(matches!(write_guard.commit(), Err(Error::Invalid { .. })));
+            file.write().await.field2 = 4;
+            file.flush().await.unwrap();
+            let backups = file.backups().await.unwrap();
+            assert_eq!(backups.len(), 1);
+
+            #[cfg(feature = "watch")]
+            {
+                fs::write(
+                    test_path,
+                    r#"{"version":1,"state":{"field1":"","field2":5}}"#,
+                )
+                .unwrap();
+                for _ in 0..200 {
+                    if file.stats().reloads > 0 {
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                assert_eq!(file.read().await.field2, 5);
+            }
+            drop(file);
+
+            for backup in backups {
+                let _ = fs::remove_file(backup.path());
+            }
+            let _ = fs::remove_file(quarantined);
+            let _ = fs::remove_file(test_path); // Clean up test file
+        });
+    }
+
+    #[test]
+    fn test_file_update() {
+        use smol::future::FutureExt;
+        use std::panic::AssertUnwindSafe;
+
+        run(|| async {
+            let test_path = "test_file_update.json";
+            let file = File::<TestData>::new(test_path).await.unwrap();
+
+            let len = file
+                .update(|state| {
+                    state.field1 = "Test String".to_string();
+                    Ok::<_, Error>(state.field1.len())
+                })
+                .await
+                .unwrap();
+            assert_eq!(len, 11);
+            assert_eq!(file.stats().writes, 1);
+
+            // a failed change is undone and not written
+            let result = file
+                .update(|state| {
+                    state.field2 = 1;
+                    Err::<(), _>(Error::ReadOnly {
+                        path: test_path.into(),
+                    })
+                })
+                .await;
+            assert!(matches!(result, Err(Error::ReadOnly { .. })));
+            assert_eq!(file.read().await.field2, 0);
+
+            let result = AssertUnwindSafe(file.update(|state| {
+                state.field2 = 2;
+                panic!("update failed");
+                #[allow(unreachable_code)]
+                Ok::<_, Error>(())
+            }))
+            .catch_unwind()
+            .await;
+            assert!(result.is_err());
+            assert_eq!(file.read().await.field2, 0);
+
+            file.flush().await.unwrap();
+            assert_eq!(file.stats().writes, 1);
+            let data: TestData =
+                serde_json::from_str(&fs::read_to_string(test_path).unwrap()).unwrap();
+            assert_eq!(data.field1, "Test String");
+            assert_eq!(data.field2, 0);
+            drop(file);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_write_guard_panic() {
+        use smol::future::FutureExt;
+        use std::panic::AssertUnwindSafe;
+
+        run(|| async {
+            let test_path = "test_write_guard_panic.json";
+            let file = File::<TestData>::new(test_path).await.unwrap();
+            let mut write_guard = file.write().await;
+            write_guard.field2 = 1;
+            write_guard.commit_async().await.unwrap();
+            let on_disk = fs::read_to_string(test_path).unwrap();
+
+            let result = AssertUnwindSafe(async {
+                let mut write_guard = file.write().await;
+                write_guard.field1 = "half".to_string();
+                panic!("mutation failed");
+            })
+            .catch_unwind()
+            .await;
+            assert!(result.is_err());
+
+            // rolled back in memory and not written
+            assert_eq!(file.read().await.field1, "");
+            file.flush().await.unwrap();
+            assert_eq!(fs::read_to_string(test_path).unwrap(), on_disk);
+            drop(file);
+
+            // the previous behaviour can be configured
+            let file = File::<TestData>::builder(test_path)
+                .on_panic(OnPanic::Write)
+                .build()
+                .await
+                .unwrap();
+            let result = AssertUnwindSafe(async {
+                let mut write_guard = file.write().await;
+                write_guard.field1 = "half".to_string();
+                panic!("mutation failed");
+            })
+            .catch_unwind()
+            .await;
+            assert!(result.is_err());
+            file.flush().await.unwrap();
+            assert!(fs::read_to_string(test_path).unwrap().contains("half"));
+            drop(file);
+
+            // changes written despite the panic are still validated
+            let file = File::<TestData>::builder(test_path)
+                .on_panic(OnPanic::Write)
+                .validate(|state| match state.field1.as_str() {
+                    "bad" => Err("field1 is bad".to_string()),
+                    _ => Ok(()),
+                })
+                .build()
+                .await
+                .unwrap();
+            let on_disk = fs::read_to_string(test_path).unwrap();
+            let result = AssertUnwindSafe(async {
+                let mut write_guard = file.write().await;
+                write_guard.field1 = "bad".to_string();
+                panic!("mutation failed");
+            })
+            .catch_unwind()
+            .await;
+            assert!(result.is_err());
+            assert!(matches!(file.flush().await, Err(Error::Invalid { .. })));
+            assert_eq!(file.read().await.field1, "half");
+            assert_eq!(fs::read_to_string(test_path).unwrap(), on_disk);
+            drop(file);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_validate() {
+        run(|| async {
+            let test_path = "test_file_validate.json";
+            let open = |recovery| {
+                File::<TestData>::builder(test_path)
+                    .validate(|state| match state.field1.as_str() {
+                        "" => Err("field1 is empty".to_string()),
+                        _ => Ok(()),
+                    })
+                    .recovery(recovery)
+                    .build()
+            };
+
+            // the default state isn't validated
+            let file = open(Recovery::Error).await.unwrap();
+            file.update(|state| {
+                state.field1 = "valid".to_string();
+                Ok::<_, Error>(())
+            })
+            .await
+            .unwrap();
+
+            // rejected changes are rolled back
+            let mut write_guard = file.write().await;
+            write_guard.field1.clear();
+            write_guard.field2 = 1;
+            let result = write_guard.commit_async().await;
+            assert!(matches!(result, Err(Error::Invalid { .. })));
+            assert_eq!(file.read().await.field2, 0);
+
+            file.write().await.field1.clear();
+            assert!(matches!(file.flush().await, Err(Error::Invalid { .. })));
+            assert_eq!(file.read().await.field1, "valid");
+
+            let result = file
+                .update(|state| {
+                    state.field1.clear();
+                    Ok::<_, Error>(())
+                })
+                .await;
+            assert!(matches!(result, Err(Error::Invalid { .. })));
+            assert_eq!(file.read().await.field1, "valid");
+            assert_eq!(file.stats().writes, 1);
+            drop(file);
+
+            // invalid files are treated as corrupt
+            fs::write(test_path, r#"{"field1":"","field2":1}"#).unwrap();
+            let result = open(Recovery::Error).await;
+            assert!(matches!(result, Err(Error::Invalid { .. })));
+
+            let file = open(Recovery::Quarantine).await.unwrap();
+            assert_eq!(*file.read().await, TestData::default());
+            let quarantined = match file.recovered() {
+                Some(Recovered::Quarantined { path, .. }) => path.clone().unwrap(),
+                _ => panic!("expected the file to be quarantined"),
+            };
+            drop(file);
+
+            let _ = fs::remove_file(quarantined);
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_subscribe() {
+        run(|| async {
+            let test_path = "test_file_subscribe.json";
+            let file = File::<TestData>::new(test_path).await.unwrap();
+            let mut subscription = file.subscribe();
+
+            // not modified
+            drop(file.write().await);
+            assert!(!subscription.has_changed());
+
+            let mut waiter = subscription.clone();
+            let (changed, ()) = smol::future::zip(waiter.changed(), async {
+                let mut write_guard = file.write().await;
+                write_guard.field2 = 1;
+                write_guard.commit_async().await.unwrap();
+            })
+            .await;
+            assert!(changed);
+
+            // changes are combined until seen
+            file.write().await.field2 = 2;
+            file.write().await.field2 = 3;
+            assert!(subscription.has_changed());
+            assert!(subscription.changed().await);
+            assert!(!subscription.has_changed());
+
+            drop(file);
+            assert!(!subscription.changed().await);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_locked() {
+        run(|| async {
+            let test_path = "test_file_locked.json";
+            let file = File::<TestData>::new(test_path).await.unwrap();
+
+            let result = File::<TestData>::new(test_path).await;
+            assert!(matches!(result, Err(Error::Locked { .. })));
+
+            drop(file);
+            let file = File::<TestData>::new(test_path).await.unwrap();
+            drop(file);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_lock_shared_read_only() {
+        run(|| async {
+            let test_path = "test_file_lock_shared_read_only.json";
+            std::fs::write(test_path, r#"{"field1":"Test String","field2":42}"#).unwrap();
+
+            let open = || {
+                File::<TestData>::builder(test_path)
+                    .lock(Lock::SharedReadOnly)
+                    .build()
+            };
+            let first = open().await.unwrap();
+            let second = open().await.unwrap();
+            assert_eq!(second.read().await.field2, 42);
+
+            let result = File::<TestData>::new(test_path).await;
+            assert!(matches!(result, Err(Error::Locked { .. })));
+
+            let mut write_guard = first.write().await;
+            write_guard.field2 = 1;
+            let result = write_guard.commit_async().await;
+            assert!(matches!(result, Err(Error::ReadOnly { .. })));
+
+            drop((first, second));
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
     }
 
-    #[tokio::test]
-    async fn test_file_read() {
-        let test_path = "test_file_read.json";
-        std::fs::write(test_path, r#"{"field1":"Test String","field2":42}"#).unwrap(); // Write initial data
+    #[test]
+    fn test_file_migrate() {
+        run(|| async {
+            let test_path = "test_file_migrate.json";
+            std::fs::write(test_path, r#"{"name":"Test String"}"#).unwrap();
 
-        let file = File::<TestData>::new(test_path).await.unwrap();
-        let read_guard = file.read().await;
+            let migrations = || {
+                Migrations::new()
+                    .step(|mut state| {
+                        state["field1"] = state["name"].take();
+                        state.as_object_mut().unwrap().remove("name");
+                        Ok::<_, String>(state)
+                    })
+                    .step(|mut state| {
+                        state["field2"] = 42.into();
+                        Ok::<_, String>(state)
+                    })
+            };
 
-        assert_eq!(read_guard.field1, "Test String");
-        assert_eq!(read_guard.field2, 42);
+            let file = File::<TestData>::builder(test_path)
+                .migrations(migrations())
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(file.read().await.field1, "Test String");
+            assert_eq!(file.read().await.field2, 42);
+            drop(file);
 
-        let _ = fs::remove_file(test_path); // Clean up test file
+            // the upgraded file was written back
+            let value: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(test_path).unwrap()).unwrap();
+            assert_eq!(
+                value,
+                serde_json::json!({
+                    "version": 2,
+                    "state": {"field1": "Test String", "field2": 42}
+                })
+            );
+
+            // a newer program upgrades it further
+            let file = File::<TestData>::builder(test_path)
+                .migrations(migrations().step(Ok::<_, String>).step(Ok::<_, String>))
+                .build()
+                .await
+                .unwrap();
+            drop(file);
+
+            // which an older program can't read
+            let result = File::<TestData>::builder(test_path)
+                .migrations(Migrations::new().step(Ok::<_, String>))
+                .build()
+                .await;
+            assert!(matches!(result, Err(Error::Migration { version: 4, .. })));
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_backups() {
+        run(|| async {
+            let test_path = "test_file_backups.json";
+            let file = File::<TestData>::builder(test_path)
+                .backups(Backups::keep(2))
+                .build()
+                .await
+                .unwrap();
+
+            for field2 in 1..=4 {
+                let mut write_guard = file.write().await;
+                write_guard.field2 = field2;
+                write_guard.commit_async().await.unwrap();
+            }
+
+            let backups = file.backups().await.unwrap();
+            assert_eq!(backups.len(), 2);
+            assert_eq!(file.load_backup(&backups[0]).await.unwrap().field2, 3);
+            assert_eq!(file.load_backup(&backups[1]).await.unwrap().field2, 2);
+
+            file.restore(&backups[1]).await.unwrap();
+            assert_eq!(file.read().await.field2, 2);
+
+            let backups = file.backups().await.unwrap();
+            assert_eq!(file.load_backup(&backups[0]).await.unwrap().field2, 4);
+            assert_eq!(file.load_backup(&backups[1]).await.unwrap().field2, 3);
+            drop(file);
+
+            let data: TestData =
+                serde_json::from_str(&fs::read_to_string(test_path).unwrap()).unwrap();
+            assert_eq!(data.field2, 2);
+
+            for backup in backups {
+                let _ = fs::remove_file(backup.path());
+            }
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_recovery_quarantine() {
+        run(|| async {
+            let test_path = "test_file_recovery_quarantine.json";
+            std::fs::write(test_path, "{\"field1\":").unwrap();
+
+            let file = File::<TestData>::builder(test_path)
+                .recovery(Recovery::Quarantine)
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(*file.read().await, TestData::default());
+
+            let quarantined = match file.recovered() {
+                Some(Recovered::Quarantined { path, error }) => {
+                    assert!(matches!(error, Error::Deserialize { .. }));
+                    path.clone().unwrap()
+                }
+                _ => panic!("expected quarantine"),
+            };
+            assert_eq!(fs::read_to_string(&quarantined).unwrap(), "{\"field1\":");
+            drop(file);
+
+            // the default state replaces the corrupt file
+            let data: TestData =
+                serde_json::from_str(&fs::read_to_string(test_path).unwrap()).unwrap();
+            assert_eq!(data, TestData::default());
+
+            let _ = fs::remove_file(quarantined);
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[test]
+    fn test_file_recovery_backup() {
+        run(|| async {
+            let test_path = "test_file_recovery_backup.json";
+            std::fs::write(test_path, "{\"field1\":").unwrap();
+            std::fs::write("test_file_recovery_backup.json.1", "{").unwrap();
+            std::fs::write(
+                "test_file_recovery_backup.json.2",
+                r#"{"field1":"Test String","field2":42}"#,
+            )
+            .unwrap();
+
+            let open = |recovery| {
+                File::<TestData>::builder(test_path)
+                    .recovery(recovery)
+                    .build()
+            };
+
+            let result = open(Recovery::Error).await;
+            assert!(matches!(result, Err(Error::Deserialize { .. })));
+
+            let file = open(Recovery::Backup).await.unwrap();
+            assert_eq!(file.read().await.field2, 42);
+            let quarantined = match file.recovered() {
+                Some(Recovered::Backup { path, backup, .. }) => {
+                    assert_eq!(backup, Path::new("test_file_recovery_backup.json.2"));
+                    path.clone().unwrap()
+                }
+                _ => panic!("expected backup"),
+            };
+            drop(file);
+
+            let _ = fs::remove_file(quarantined);
+            let _ = fs::remove_file("test_file_recovery_backup.json.1");
+            let _ = fs::remove_file("test_file_recovery_backup.json.2");
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_permissions() {
+        run(|| async {
+            use std::os::unix::fs::PermissionsExt;
+
+            let test_dir = "test_file_permissions";
+            let test_path = "test_file_permissions/nested/state.json";
+            let mode = |path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+            let permissions = Permissions::new().mode(0o600).create_dirs(0o700);
+            let file = File::<TestData>::builder(test_path)
+                .permissions(permissions.clone())
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(mode("test_file_permissions/nested"), 0o700);
+            assert_eq!(mode(test_path), 0o600);
+
+            // replaced by a temporary file
+            file.write().await.field2 = 1;
+            file.flush().await.unwrap();
+            assert_eq!(mode(test_path), 0o600);
+            drop(file);
+
+            fs::set_permissions(test_path, fs::Permissions::from_mode(0o644)).unwrap();
+            let result = File::<TestData>::builder(test_path)
+                .permissions(permissions.clone().strict())
+                .build()
+                .await;
+            assert!(matches!(
+                result,
+                Err(Error::Permissions { mode: 0o644, .. })
+            ));
+
+            // without a mode the existing one is kept
+            let file = File::<TestData>::new(test_path).await.unwrap();
+            file.write().await.field2 = 2;
+            file.flush().await.unwrap();
+            assert_eq!(mode(test_path), 0o644);
+            drop(file);
+
+            let _ = fs::remove_dir_all(test_dir); // Clean up test directory
+        });
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn test_file_watch() {
+        run(|| async {
+            let test_path = "test_file_watch.json";
+            let file = File::<TestData>::builder(test_path)
+                .watch()
+                .build()
+                .await
+                .unwrap();
+
+            // poll until the watcher has caught up
+            fn wait_for<R>(mut f: impl FnMut() -> Option<R>) -> Option<R> {
+                for _ in 0..200 {
+                    if let Some(result) = f() {
+                        return Some(result);
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                None
+            }
+
+            // our own writes aren't reloaded
+            file.write().await.field2 = 1;
+            file.flush().await.unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+            assert_eq!(file.stats().reloads, 0);
+
+            let mut subscription = file.subscribe();
+            fs::write(test_path, r#"{"field1":"edited","field2":2}"#).unwrap();
+            assert!(wait_for(|| (file.stats().reloads > 0).then_some(())).is_some());
+            assert_eq!(file.read().await.field1, "edited");
+            assert_eq!(file.read().await.field2, 2);
+            assert!(subscription.changed().await);
+            assert!(!subscription.has_changed());
+
+            // a broken edit leaves the state alone
+            fs::write(test_path, "{").unwrap();
+            let error = wait_for(|| file.reload_error());
+            assert!(matches!(error, Some(Error::Deserialize { .. })));
+            assert_eq!(file.read().await.field2, 2);
+            drop(file);
+
+            // changing the format afterwards keeps the file watched
+            fs::write(test_path, "").unwrap();
+            let file = File::<TestData>::builder(test_path)
+                .watch()
+                .compact()
+                .build()
+                .await
+                .unwrap();
+            fs::write(test_path, r#"{"field1":"compact","field2":3}"#).unwrap();
+            assert!(wait_for(|| (file.stats().reloads > 0).then_some(())).is_some());
+            assert_eq!(file.read().await.field1, "compact");
+            drop(file);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
     }
 
-    #[tokio::test]
-    async fn test_file_read_default() {
-        let test_path = "test_file_read_default.json";
-        std::fs::write(test_path, "").unwrap(); // Write empty file
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn test_file_integrity() {
+        run(|| async {
+            let test_path = "test_file_integrity.json";
+            let open = |recovery| {
+                File::<TestData>::builder(test_path)
+                    .integrity(Integrity::Crc32c)
+                    .recovery(recovery)
+                    .build()
+            };
+
+            let file = open(Recovery::Error).await.unwrap();
+            file.write().await.field2 = 42;
+            drop(file);
+
+            let file = open(Recovery::Error).await.unwrap();
+            assert_eq!(file.read().await.field2, 42);
+            drop(file);
+
+            // corrupt a digit, leaving valid JSON
+            let mut bytes = fs::read(test_path).unwrap();
+            let pos = bytes.iter().position(|&b| b == b'4').unwrap();
+            bytes[pos] = b'5';
+            fs::write(test_path, &bytes).unwrap();
+
+            let result = open(Recovery::Error).await;
+            assert!(matches!(result, Err(Error::Integrity { .. })));
+
+            // a file without the checksum isn't trusted either
+            let start = bytes.iter().position(|&b| b == b'{').unwrap();
+            fs::write(test_path, &bytes[start..]).unwrap();
+            let result = open(Recovery::Error).await;
+            assert!(matches!(result, Err(Error::Integrity { .. })));
+
+            let file = open(Recovery::Quarantine).await.unwrap();
+            assert_eq!(file.read().await.field2, 0);
+            let quarantined = match file.recovered() {
+                Some(Recovered::Quarantined { path, .. }) => path.clone().unwrap(),
+                _ => panic!("expected quarantine"),
+            };
+            drop(file);
+
+            let _ = fs::remove_file(quarantined);
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_file_compression() {
+        run(|| async {
+            let test_path = "test_file_compression.json";
+            std::fs::write(test_path, r#"{"field1":"uncompressed","field2":1}"#).unwrap();
+
+            let open = || {
+                File::<TestData>::builder(test_path)
+                    .compression(Compression::Zstd)
+                    .build()
+            };
+
+            // existing files are still read
+            let file = open().await.unwrap();
+            assert_eq!(file.read().await.field1, "uncompressed");
+            file.write().await.field2 = 2;
+            drop(file);
+
+            let contents = fs::read(test_path).unwrap();
+            assert!(contents.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+
+            // detected without being configured
+            let file = File::<TestData>::new(test_path).await.unwrap();
+            assert_eq!(file.read().await.field1, "uncompressed");
+            assert_eq!(file.read().await.field2, 2);
+            drop(file);
+
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_file_encryption() {
+        run(|| async {
+            let test_path = "test_file_encryption.json";
+            std::fs::write(test_path, r#"{"field1":"secret","field2":42}"#).unwrap();
+
+            let open = |key| File::<TestData>::builder(test_path).encryption(key).build();
+            let old_key = Key::from_bytes([1; 32]);
+            let new_key = Key::from_bytes([2; 32]);
+
+            // plaintext is encrypted when opened
+            let file = open(old_key.clone()).await.unwrap();
+            assert_eq!(file.read().await.field1, "secret");
+            let contents = fs::read(test_path).unwrap();
+            assert!(!contents.windows(6).any(|w| w == b"secret"));
+
+            file.rotate_key(new_key.clone()).await.unwrap();
+            file.write().await.field2 = 1;
+            drop(file);
 
-        let file = File::<TestData>::new(test_path).await.unwrap();
-        let read_guard = file.read().await;
+            let result = open(old_key).await;
+            assert!(matches!(result, Err(Error::Encryption { .. })));
 
-        // Check default values
-        assert_eq!(read_guard.field1, "");
-        assert_eq!(read_guard.field2, 0);
+            let file = open(new_key).await.unwrap();
+            assert_eq!(file.read().await.field1, "secret");
+            assert_eq!(file.read().await.field2, 1);
+            drop(file);
 
-        let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(test_path); // Clean up test file
+            let _ = fs::remove_file(format!("{}.lock", test_path));
+        });
     }
 }
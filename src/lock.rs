@@ -0,0 +1,127 @@
+use crate::disk;
+use crate::Error;
+use std::fs::{OpenOptions, TryLockError};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a lock held by another process is retried while waiting.
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How a state file is locked against use by other processes.
+///
+/// Locks are advisory and taken on a `.lock` file next to the state file, so
+/// they only coordinate processes that use them. The lock is held until the
+/// [`File`](crate::File) is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lock {
+    /// Don't lock the file.
+    None,
+    /// Take an exclusive lock, failing immediately if another process holds
+    /// one.
+    #[default]
+    FailFast,
+    /// Take an exclusive lock, waiting up to the given duration for another
+    /// process to release it.
+    Wait(Duration),
+    /// Take a lock that can be shared with other readers, which excludes any
+    /// writer. The state can't be written while holding it.
+    SharedReadOnly,
+}
+
+impl Lock {
+    /// Whether this lock prevents writing the state file.
+    pub(crate) fn is_read_only(self) -> bool {
+        self == Lock::SharedReadOnly
+    }
+
+    /// Lock the state file at `path`, returning the handle holding the lock.
+    pub(crate) fn acquire(self, path: &Path) -> Result<Option<std::fs::File>, Error> {
+        if self == Lock::None {
+            return Ok(None);
+        }
+
+        let lock_path = disk::sibling(path, ".lock");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|e| Error::io(&lock_path, "open", e))?;
+
+        let deadline = match self {
+            Lock::Wait(timeout) => Some(Instant::now() + timeout),
+            _ => None,
+        };
+
+        loop {
+            let result = match self {
+                Lock::SharedReadOnly => file.try_lock_shared(),
+                _ => file.try_lock(),
+            };
+
+            match result {
+                Ok(()) => return Ok(Some(file)),
+                Err(TryLockError::Error(e)) => return Err(Error::io(&lock_path, "lock", e)),
+                Err(TryLockError::WouldBlock) => match deadline {
+                    Some(deadline) if Instant::now() < deadline => thread::sleep(RETRY_INTERVAL),
+                    _ => {
+                        return Err(Error::Locked {
+                            path: path.to_path_buf(),
+                        })
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_exclusive() {
+        let test_path = Path::new("test_lock_exclusive.json");
+
+        let held = Lock::FailFast.acquire(test_path).unwrap();
+        assert!(matches!(
+            Lock::FailFast.acquire(test_path),
+            Err(Error::Locked { .. })
+        ));
+        assert!(matches!(
+            Lock::SharedReadOnly.acquire(test_path),
+            Err(Error::Locked { .. })
+        ));
+
+        let start = Instant::now();
+        assert!(matches!(
+            Lock::Wait(Duration::from_millis(50)).acquire(test_path),
+            Err(Error::Locked { .. })
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        drop(held);
+        assert!(Lock::FailFast.acquire(test_path).unwrap().is_some());
+
+        let _ = std::fs::remove_file("test_lock_exclusive.json.lock"); // Clean up test file
+    }
+
+    #[test]
+    fn test_lock_shared() {
+        let test_path = Path::new("test_lock_shared.json");
+
+        let first = Lock::SharedReadOnly.acquire(test_path).unwrap();
+        let second = Lock::SharedReadOnly.acquire(test_path).unwrap();
+        assert!(matches!(
+            Lock::FailFast.acquire(test_path),
+            Err(Error::Locked { .. })
+        ));
+        drop((first, second));
+
+        assert!(Lock::None.acquire(test_path).unwrap().is_none());
+
+        let _ = std::fs::remove_file("test_lock_shared.json.lock"); // Clean up test file
+    }
+}
@@ -0,0 +1,170 @@
+use crate::format::Format;
+use crate::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+use std::path::Path;
+
+type Step = Box<dyn Fn(Value) -> Result<Value, String> + Send + Sync>;
+
+/// Upgrades applied to state files written by older versions of a program.
+///
+/// Each step is a function taking the state at version `N` and returning it at
+/// version `N + 1`, starting from version 0. The number of steps is the current
+/// version. Files are stored in an envelope recording their version, files
+/// without one are treated as version 0.
+///
+/// Steps operate on a [`serde_json::Value`] regardless of the file's format, so
+/// migrations require a self-describing format.
+///
+/// ```rust
+/// use statefile::blocking::File;
+/// use statefile::Migrations;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize, Default)]
+/// struct State {
+///     hostname: String,
+/// }
+///
+/// let migrations = Migrations::new()
+///     // version 1 renamed `host` to `hostname`
+///     .step(|mut state| {
+///         let host = state.as_object_mut().and_then(|s| s.remove("host"));
+///         state["hostname"] = host.ok_or("missing host")?;
+///         Ok::<_, &str>(state)
+///     });
+///
+/// let state = File::<State>::builder("migrations.json")
+///     .migrations(migrations)
+///     .build_blocking()
+///     .unwrap();
+/// # drop(state);
+/// # std::fs::remove_file("migrations.json").unwrap();
+/// # std::fs::remove_file("migrations.json.lock").unwrap();
+/// ```
+#[derive(Default)]
+pub struct Migrations {
+    steps: Vec<Step>,
+}
+
+/// The shape of a versioned state file.
+#[derive(Serialize)]
+pub(crate) struct Envelope<'a, T> {
+    pub(crate) version: u32,
+    pub(crate) state: &'a T,
+}
+
+impl Migrations {
+    /// Create a registry without any steps, at version 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a step upgrading the state from the current version to the next.
+    pub fn step<E: fmt::Display>(
+        mut self,
+        step: impl Fn(Value) -> Result<Value, E> + Send + Sync + 'static,
+    ) -> Self {
+        self.steps.push(Box::new(move |value| {
+            step(value).map_err(|e| e.to_string())
+        }));
+        self
+    }
+
+    /// The current version, which files are upgraded to.
+    pub fn version(&self) -> u32 {
+        self.steps.len() as u32
+    }
+
+    /// Deserialize a versioned file, upgrading it to the current version.
+    ///
+    /// Also returns the version the file was stored at.
+    pub(crate) fn load<T: DeserializeOwned>(
+        &self,
+        format: &impl Format,
+        path: &Path,
+        contents: &[u8],
+    ) -> Result<(T, u32), Error> {
+        let value: Value = format
+            .deserialize(contents)
+            .map_err(|e| Error::deserialize(path, e))?;
+
+        let (version, mut state) = split(value);
+        let error = |version, reason| Error::Migration {
+            path: path.to_path_buf(),
+            version,
+            reason,
+        };
+
+        if version > self.version() {
+            let reason = format!(
+                "file version is newer than the supported version {}",
+                self.version()
+            );
+            return Err(error(version, reason));
+        }
+
+        for (from, step) in self.steps.iter().enumerate().skip(version as usize) {
+            state = step(state).map_err(|reason| error(from as u32, reason))?;
+        }
+
+        let state = serde_json::from_value(state).map_err(|e| Error::Deserialize {
+            path: path.to_path_buf(),
+            line: None,
+            column: None,
+            source: e.into(),
+        })?;
+
+        Ok((state, version))
+    }
+}
+
+/// Split a file into its version and state.
+fn split(value: Value) -> (u32, Value) {
+    match value {
+        Value::Object(mut map) if map.len() == 2 && map.contains_key("state") => {
+            let version = map.get("version").and_then(Value::as_u64);
+            match version.and_then(|v| u32::try_from(v).ok()) {
+                Some(version) => (version, map.remove("state").unwrap_or_default()),
+                None => (0, Value::Object(map)),
+            }
+        }
+        value => (0, value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Json;
+    use serde_json::json;
+
+    #[test]
+    fn test_split() {
+        let (version, state) = split(json!({"version": 3, "state": {"a": 1}}));
+        assert_eq!((version, state), (3, json!({"a": 1})));
+
+        // anything else is unversioned
+        let value = json!({"version": 3, "other": 1});
+        assert_eq!(split(value.clone()), (0, value));
+        let value = json!({"version": 3});
+        assert_eq!(split(value.clone()), (0, value));
+        assert_eq!(split(json!(42)), (0, json!(42)));
+    }
+
+    #[test]
+    fn test_migrate_failed_step() {
+        let migrations = Migrations::new()
+            .step(Ok::<_, String>)
+            .step(|_| Err("no good"));
+
+        let contents = br#"{"version": 1, "state": 1}"#;
+        let result = migrations.load::<u32>(&Json, Path::new("state.json"), contents);
+        assert!(matches!(
+            result,
+            Err(Error::Migration { version: 1, ref reason, .. }) if reason == "no good"
+        ));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{disk, Error};
+use std::path::Path;
+
+/// Permissions and ownership given to a state file when it's created or
+/// written.
+///
+/// These only have an effect on Unix. Without a mode, writes keep the mode of
+/// the file being replaced.
+///
+/// ```no_run
+/// # use statefile::blocking::File;
+/// # use statefile::Permissions;
+/// # #[derive(Default, serde::Serialize, serde::Deserialize)]
+/// # struct State;
+/// # fn open() -> Result<(), statefile::Error> {
+/// let file = File::<State>::builder("/var/lib/app/state.json")
+///     .permissions(Permissions::new().mode(0o600).create_dirs(0o700))
+///     .build_blocking()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Permissions {
+    mode: Option<u32>,
+    owner: Option<u32>,
+    group: Option<u32>,
+    dir_mode: Option<u32>,
+    strict: bool,
+}
+
+impl Permissions {
+    /// Permissions that leave the mode and ownership as the operating system
+    /// sets them, the same as [`Permissions::default`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the permission bits of the file, e.g. `0o600`.
+    ///
+    /// An existing file with any other bits set is restricted to this mode
+    /// with a warning when opened, see [`Permissions::strict`].
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Set the user id owning the file. Changing the owner usually requires
+    /// privileges.
+    pub fn owner(mut self, uid: u32) -> Self {
+        self.owner = Some(uid);
+        self
+    }
+
+    /// Set the group id owning the file.
+    pub fn group(mut self, gid: u32) -> Self {
+        self.group = Some(gid);
+        self
+    }
+
+    /// Create any missing parent directories with the given mode, owned by
+    /// the configured owner and group.
+    pub fn create_dirs(mut self, mode: u32) -> Self {
+        self.dir_mode = Some(mode);
+        self
+    }
+
+    /// Fail with [`Error::Permissions`] when an existing file is more
+    /// permissive than the configured mode, rather than restricting it.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::fs::{self, DirBuilder, OpenOptions};
+    use std::io;
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+
+    impl Permissions {
+        /// Create the directories above `path` if configured to.
+        pub(crate) fn create_parent(&self, path: &Path) -> Result<(), Error> {
+            match self.dir_mode {
+                Some(mode) => self.create_dir(disk::parent(path), mode),
+                None => Ok(()),
+            }
+        }
+
+        fn create_dir(&self, dir: &Path, mode: u32) -> Result<(), Error> {
+            if dir.is_dir() {
+                return Ok(());
+            }
+            if let Some(parent) = dir.parent().filter(|p| !p.as_os_str().is_empty()) {
+                self.create_dir(parent, mode)?;
+            }
+
+            match DirBuilder::new().mode(mode).create(dir) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+                Err(e) => return Err(Error::io(dir, "create", e)),
+            }
+            // the umask may have removed some of the bits
+            fs::set_permissions(dir, fs::Permissions::from_mode(mode))
+                .map_err(|e| Error::io(dir, "set permissions of", e))?;
+            self.chown(dir)
+        }
+
+        /// Prepare to create the file with no more than the configured mode.
+        pub(crate) fn create_mode(&self, options: &mut OpenOptions) {
+            if let Some(mode) = self.mode {
+                options.mode(mode);
+            }
+        }
+
+        /// Give the newly created `file` at `path` its permissions and owner.
+        ///
+        /// If no mode is configured the mode of `original`, the file it will
+        /// replace, is kept.
+        pub(crate) fn apply(
+            &self,
+            file: &fs::File,
+            path: &Path,
+            original: &Path,
+        ) -> Result<(), Error> {
+            let mode = match self.mode {
+                Some(mode) => Some(mode),
+                None => match fs::metadata(original) {
+                    Ok(metadata) => Some(metadata.mode() & 0o7777),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(Error::io(original, "stat", e)),
+                },
+            };
+            if let Some(mode) = mode {
+                file.set_permissions(fs::Permissions::from_mode(mode))
+                    .map_err(|e| Error::io(path, "set permissions of", e))?;
+            }
+
+            if self.owner.is_some() || self.group.is_some() {
+                std::os::unix::fs::fchown(file, self.owner, self.group)
+                    .map_err(|e| Error::io(path, "change owner of", e))?;
+            }
+            Ok(())
+        }
+
+        /// Check that the existing file at `path` isn't more permissive than
+        /// the configured mode, restricting it unless strict or `read_only`.
+        pub(crate) fn check(&self, path: &Path, read_only: bool) -> Result<(), Error> {
+            let required = match self.mode {
+                Some(mode) => mode,
+                None => return Ok(()),
+            };
+            let mode = match fs::metadata(path) {
+                Ok(metadata) => metadata.mode() & 0o7777,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(Error::io(path, "stat", e)),
+            };
+            if mode & !required == 0 {
+                return Ok(());
+            }
+
+            let error = Error::Permissions {
+                path: path.to_path_buf(),
+                mode,
+                required,
+            };
+            if self.strict {
+                return Err(error);
+            }
+
+            log::warn!("{}", error);
+            if !read_only {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode & required))
+                    .map_err(|e| Error::io(path, "set permissions of", e))?;
+            }
+            Ok(())
+        }
+
+        fn chown(&self, path: &Path) -> Result<(), Error> {
+            if self.owner.is_some() || self.group.is_some() {
+                std::os::unix::fs::chown(path, self.owner, self.group)
+                    .map_err(|e| Error::io(path, "change owner of", e))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+
+    /// Permissions aren't supported on this platform, apart from creating
+    /// directories.
+    impl Permissions {
+        pub(crate) fn create_parent(&self, path: &Path) -> Result<(), Error> {
+            if self.dir_mode.is_some() {
+                let parent = disk::parent(path);
+                fs::create_dir_all(parent).map_err(|e| Error::io(parent, "create", e))?;
+            }
+            Ok(())
+        }
+
+        pub(crate) fn create_mode(&self, _options: &mut OpenOptions) {}
+
+        pub(crate) fn apply(
+            &self,
+            _file: &fs::File,
+            _path: &Path,
+            _original: &Path,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+
+        pub(crate) fn check(&self, _path: &Path, _read_only: bool) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn mode(path: &str) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn test_create_dirs() {
+        let test_dir = "test_permissions_create_dirs";
+        let path = format!("{}/nested/state.json", test_dir);
+        Permissions::new()
+            .create_dirs(0o700)
+            .create_parent(Path::new(&path))
+            .unwrap();
+        assert_eq!(mode(test_dir), 0o700);
+        assert_eq!(mode(&format!("{}/nested", test_dir)), 0o700);
+
+        let _ = fs::remove_dir_all(test_dir); // Clean up test directory
+    }
+
+    #[test]
+    fn test_check() {
+        let test_path = "test_permissions_check.json";
+        fs::write(test_path, "{}").unwrap();
+        fs::set_permissions(test_path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let permissions = Permissions::new().mode(0o600);
+        assert!(matches!(
+            permissions
+                .clone()
+                .strict()
+                .check(Path::new(test_path), false),
+            Err(Error::Permissions {
+                mode: 0o644,
+                required: 0o600,
+                ..
+            })
+        ));
+        assert_eq!(mode(test_path), 0o644);
+
+        // read-only handles leave the file alone
+        permissions.check(Path::new(test_path), true).unwrap();
+        assert_eq!(mode(test_path), 0o644);
+
+        permissions.check(Path::new(test_path), false).unwrap();
+        assert_eq!(mode(test_path), 0o600);
+
+        // less permissive is fine
+        fs::set_permissions(test_path, fs::Permissions::from_mode(0o400)).unwrap();
+        permissions
+            .strict()
+            .check(Path::new(test_path), false)
+            .unwrap();
+
+        let _ = fs::remove_file(test_path); // Clean up test file
+    }
+}
@@ -0,0 +1,108 @@
+use crate::format::{Format, Json};
+use async_lock::{RwLock, RwLockReadGuard};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+/// A state file opened with [`File::open_read_only`](crate::File::open_read_only)
+/// or [`FileBuilder::build_read_only`](crate::FileBuilder::build_read_only).
+///
+/// The file is never created, locked or written. Only read access is
+/// available, so the following doesn't compile:
+///
+/// ```compile_fail
+/// # async fn f() {
+/// let state = statefile::File::<u32>::open_read_only("state.json").await.unwrap();
+/// *state.write().await = 1;
+/// # }
+/// ```
+pub struct ReadOnlyFile<T: Serialize + DeserializeOwned + Default, F: Format = Json> {
+    data: RwLock<T>,
+    path: PathBuf,
+    _format: PhantomData<F>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default, F: Format> ReadOnlyFile<T, F> {
+    pub(crate) fn new(state: T, path: PathBuf) -> Self {
+        ReadOnlyFile {
+            data: RwLock::new(state),
+            path,
+            _format: PhantomData,
+        }
+    }
+
+    /// Locks this state file with shared read access, causing the current task
+    /// to yield until the lock has been acquired.
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.data.read().await
+    }
+
+    /// The path of the state file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::run;
+    use crate::{Error, File, Migrations};
+
+    #[test]
+    fn test_read_only_missing() {
+        run(|| async {
+            let test_path = "test_read_only_missing.json";
+
+            let result = File::<u32>::open_read_only(test_path).await;
+            assert!(matches!(result, Err(Error::Io { op: "open", .. })));
+
+            // nothing is created as a side effect
+            assert!(!Path::new(test_path).exists());
+            assert!(!Path::new("test_read_only_missing.json.lock").exists());
+        });
+    }
+
+    #[test]
+    fn test_read_only_while_locked() {
+        run(|| async {
+            let test_path = "test_read_only_while_locked.json";
+            std::fs::write(test_path, "42").unwrap();
+
+            // readers aren't blocked by the process owning the file
+            let file = File::<u32>::new(test_path).await.unwrap();
+            let read_only = File::<u32>::open_read_only(test_path).await.unwrap();
+            assert_eq!(*read_only.read().await, 42);
+            drop(file);
+
+            let _ = std::fs::remove_file(test_path); // Clean up test file
+            let _ = std::fs::remove_file("test_read_only_while_locked.json.lock");
+        });
+    }
+
+    #[test]
+    fn test_read_only_migrated() {
+        run(|| async {
+            let test_path = "test_read_only_migrated.json";
+            let contents = r#"{"version":1,"state":41}"#;
+            std::fs::write(test_path, contents).unwrap();
+
+            let migrations = Migrations::new()
+                .step(Ok::<_, String>)
+                .step(|state| Ok::<_, String>((state.as_u64().unwrap() + 1).into()));
+            let read_only = File::<u32>::builder(test_path)
+                .migrations(migrations)
+                .build_read_only()
+                .await
+                .unwrap();
+            assert_eq!(*read_only.read().await, 42);
+
+            // the upgrade isn't written back
+            assert_eq!(std::fs::read_to_string(test_path).unwrap(), contents);
+            assert!(!Path::new("test_read_only_migrated.json.lock").exists());
+
+            let _ = std::fs::remove_file(test_path); // Clean up test file
+        });
+    }
+}
@@ -0,0 +1,207 @@
+use crate::codec::Codec;
+use crate::format::Format;
+use crate::validate::{self, Validator};
+use crate::{backup, disk, Error};
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// What to do when a state file exists but can't be deserialized, or is
+/// rejected by validation.
+///
+/// Files written by a newer version of the program, or that fail to migrate,
+/// are never treated as corrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Recovery {
+    /// Fail to open the file.
+    #[default]
+    Error,
+    /// Move the file to `<file>.corrupt-<timestamp>` and start from the default
+    /// state. A number is appended to the name if it's already taken, so
+    /// earlier quarantined files are never replaced.
+    Quarantine,
+    /// Move the file aside as with [`Recovery::Quarantine`] and start from the
+    /// newest backup that can be deserialized. Fails to open the file if there
+    /// isn't one.
+    Backup,
+}
+
+/// The action taken to recover from a corrupt state file.
+#[derive(Debug)]
+pub enum Recovered {
+    /// The file was moved aside and the default state used.
+    Quarantined {
+        /// Where the corrupt file was moved to, or `None` if it was left in
+        /// place because the file was opened read-only.
+        path: Option<PathBuf>,
+        /// Why the file couldn't be used.
+        error: Error,
+    },
+    /// The file was moved aside and the state restored from a backup.
+    Backup {
+        /// Where the corrupt file was moved to, or `None` if it was left in
+        /// place because the file was opened read-only.
+        path: Option<PathBuf>,
+        /// The backup that was restored.
+        backup: PathBuf,
+        /// Why the file couldn't be used.
+        error: Error,
+    },
+}
+
+impl Recovered {
+    /// Why the file couldn't be used.
+    pub fn error(&self) -> &Error {
+        match self {
+            Recovered::Quarantined { error, .. } | Recovered::Backup { error, .. } => error,
+        }
+    }
+}
+
+impl Recovery {
+    /// Recover the state of the file at `path`, which failed to deserialize
+    /// or validate with `error`. Backups must pass `validator` to be used.
+    ///
+    /// The corrupt file is only moved aside if `quarantine` is set.
+    pub(crate) fn recover<T: DeserializeOwned + Default, F: Format>(
+        self,
+        codec: &Codec<F>,
+        validator: Option<&Validator<T>>,
+        path: &Path,
+        error: Error,
+        quarantine: bool,
+    ) -> Result<(T, Recovered), Error> {
+        let corrupt = matches!(
+            error,
+            Error::Deserialize { .. } | Error::Integrity { .. } | Error::Invalid { .. }
+        );
+        if self == Recovery::Error || !corrupt {
+            return Err(error);
+        }
+
+        let restored = match self {
+            Recovery::Backup => match newest_valid_backup(codec, validator, path)? {
+                Some(restored) => Some(restored),
+                None => return Err(error),
+            },
+            _ => None,
+        };
+
+        let quarantined = match quarantine {
+            true => Some(move_aside(path)?),
+            false => None,
+        };
+        log::warn!(
+            "Recovering from corrupt state file {}: {}",
+            path.display(),
+            error
+        );
+
+        Ok(match restored {
+            Some((state, backup)) => (
+                state,
+                Recovered::Backup {
+                    path: quarantined,
+                    backup,
+                    error,
+                },
+            ),
+            None => (
+                T::default(),
+                Recovered::Quarantined {
+                    path: quarantined,
+                    error,
+                },
+            ),
+        })
+    }
+}
+
+/// Move the corrupt file at `path` to an unused `<file>.corrupt-<timestamp>`
+/// name, returning where it was moved to.
+fn move_aside(path: &Path) -> Result<PathBuf, Error> {
+    let timestamp = timestamp();
+    for n in 0.. {
+        let suffix = match n {
+            0 => format!(".corrupt-{}", timestamp),
+            n => format!(".corrupt-{}-{}", timestamp, n),
+        };
+        let quarantined = disk::sibling(path, &suffix);
+
+        // claim the name first, since rename replaces an existing file
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&quarantined)
+        {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(Error::io(&quarantined, "quarantine", e)),
+        }
+
+        return match std::fs::rename(path, &quarantined) {
+            Ok(()) => Ok(quarantined),
+            Err(e) => {
+                let _ = std::fs::remove_file(&quarantined);
+                Err(Error::io(path, "quarantine", e))
+            }
+        };
+    }
+    unreachable!("ran out of quarantine names")
+}
+
+/// Find the newest backup of `path` that can be deserialized and is valid.
+fn newest_valid_backup<T: DeserializeOwned + Default, F: Format>(
+    codec: &Codec<F>,
+    validator: Option<&Validator<T>>,
+    path: &Path,
+) -> Result<Option<(T, PathBuf)>, Error> {
+    for backup in backup::list(path)? {
+        let contents =
+            std::fs::read(backup.path()).map_err(|e| Error::io(backup.path(), "read", e))?;
+
+        let result = codec.decode(backup.path(), &contents).and_then(|decoded| {
+            validate::check(validator, backup.path(), &decoded.state)?;
+            Ok(decoded)
+        });
+        match result {
+            Ok(decoded) => return Ok(Some((decoded.state, backup.path().to_path_buf()))),
+            Err(e) => log::warn!("Skipping unusable backup: {}", e),
+        }
+    }
+
+    Ok(None)
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_move_aside_keeps_earlier_files() {
+        let test_path = Path::new("test_move_aside_keeps_earlier_files.json");
+
+        let mut quarantined = Vec::new();
+        for contents in ["first", "second"] {
+            fs::write(test_path, contents).unwrap();
+            quarantined.push(move_aside(test_path).unwrap());
+        }
+
+        assert!(!test_path.exists());
+        assert_ne!(quarantined[0], quarantined[1]);
+        assert_eq!(fs::read_to_string(&quarantined[0]).unwrap(), "first");
+        assert_eq!(fs::read_to_string(&quarantined[1]).unwrap(), "second");
+
+        for path in quarantined {
+            let _ = fs::remove_file(path); // Clean up test file
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use crate::Error;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+
+/// Find the directory a service should keep its state in, creating it if it
+/// doesn't exist.
+///
+/// The first of these that is available is used:
+///
+/// 1. `$STATE_DIRECTORY`, set by systemd for services configured with
+///    `StateDirectory=`. If it lists several directories separated by colons
+///    the first is used.
+/// 2. `$XDG_STATE_HOME/<app>`, or `~/.local/state/<app>` when not running as
+///    root, for user services and programs run from a shell.
+/// 3. `/var/lib/<app>`.
+///
+/// `<app>` is the file name of the running executable. The directory chosen
+/// is logged, and the full path of a file opened within it is available from
+/// [`File::path`](crate::File::path).
+pub fn state_directory() -> Result<PathBuf, Error> {
+    let exe = std::env::current_exe().map_err(|e| Error::StateDirectory {
+        reason: format!("failed to find the executable: {}", e),
+    })?;
+    let app = exe.file_stem().ok_or_else(|| Error::StateDirectory {
+        reason: format!("{} has no file name", exe.display()),
+    })?;
+
+    let dir = resolve(|name| std::env::var_os(name), app, is_root()).ok_or_else(|| {
+        Error::StateDirectory {
+            reason: "none of STATE_DIRECTORY, XDG_STATE_HOME or HOME is set".to_string(),
+        }
+    })?;
+
+    create(&dir)?;
+    log::info!("Using state directory {}", dir.display());
+    Ok(dir)
+}
+
+/// Choose the state directory for `app` given a way to look up environment
+/// variables.
+fn resolve(var: impl Fn(&str) -> Option<OsString>, app: &OsStr, root: bool) -> Option<PathBuf> {
+    // empty variables are treated as unset
+    let var = |name| var(name).filter(|value| !value.is_empty());
+
+    if let Some(dirs) = var("STATE_DIRECTORY") {
+        if let Some(dir) = std::env::split_paths(&dirs).find(|d| !d.as_os_str().is_empty()) {
+            return Some(dir);
+        }
+    }
+    if let Some(dir) = var("XDG_STATE_HOME") {
+        return Some(PathBuf::from(dir).join(app));
+    }
+    if !root {
+        if let Some(home) = var("HOME") {
+            return Some(PathBuf::from(home).join(".local/state").join(app));
+        }
+    }
+    if cfg!(unix) {
+        return Some(PathBuf::from("/var/lib").join(app));
+    }
+    None
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and always succeeds
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+/// Create `dir` and its parents, readable only by the current user as the
+/// XDG base directory specification requires.
+fn create(dir: &std::path::Path) -> Result<(), Error> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir).map_err(|e| Error::io(dir, "create", e))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn resolve_with(vars: &[(&str, &str)], root: bool) -> Option<PathBuf> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        resolve(
+            |name| vars.get(name).map(OsString::from),
+            OsStr::new("app"),
+            root,
+        )
+    }
+
+    #[test]
+    fn test_state_directory_resolve() {
+        let systemd = [
+            ("STATE_DIRECTORY", "/var/lib/app:/var/lib/other"),
+            ("XDG_STATE_HOME", "/home/user/.state"),
+            ("HOME", "/home/user"),
+        ];
+        assert_eq!(
+            resolve_with(&systemd, true),
+            Some(PathBuf::from("/var/lib/app"))
+        );
+
+        let user = [
+            ("XDG_STATE_HOME", "/home/user/.state"),
+            ("HOME", "/home/user"),
+        ];
+        assert_eq!(
+            resolve_with(&user, false),
+            Some(PathBuf::from("/home/user/.state/app"))
+        );
+
+        let shell = [("STATE_DIRECTORY", ""), ("HOME", "/home/user")];
+        assert_eq!(
+            resolve_with(&shell, false),
+            Some(PathBuf::from("/home/user/.local/state/app"))
+        );
+
+        // root's home isn't used for system services
+        let system = [("HOME", "/root")];
+        assert_eq!(
+            resolve_with(&system, true),
+            Some(PathBuf::from("/var/lib/app"))
+        );
+    }
+}
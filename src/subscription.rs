@@ -0,0 +1,107 @@
+use event_listener::Event;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Changes to the state of a [`File`](crate::File), shared with its
+/// subscriptions.
+#[derive(Debug, Default)]
+pub(crate) struct Changes {
+    /// Incremented on each change.
+    version: AtomicU64,
+    /// Set once the file has been dropped.
+    closed: AtomicBool,
+    event: Event,
+}
+
+impl Changes {
+    /// Wake every subscription with the news of a change.
+    pub(crate) fn notify(&self) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+        self.event.notify(usize::MAX);
+    }
+
+    /// Wake every subscription for the last time.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.event.notify(usize::MAX);
+    }
+
+    pub(crate) fn subscribe(self: &Arc<Self>) -> Subscription {
+        Subscription {
+            seen: self.version.load(Ordering::SeqCst),
+            changes: self.clone(),
+        }
+    }
+}
+
+/// Notifies a task when the state of a [`File`](crate::File) changes, created
+/// with [`File::subscribe`](crate::File::subscribe).
+///
+/// A change is seen when a [`WriteGuard`](crate::WriteGuard) that modified the
+/// state is released, whether it was committed, dropped or abandoned, and when
+/// the state is reloaded after the file was changed by another process.
+/// Changes made while nobody is waiting are combined into one notification.
+///
+/// ```rust
+/// # use statefile::File;
+/// # #[tokio::main]
+/// # async fn main() {
+/// let file = File::<u32>::new("subscription.json").await.unwrap();
+/// let mut changes = file.subscribe();
+///
+/// *file.write().await = 1;
+/// assert!(changes.changed().await);
+/// assert_eq!(*file.read().await, 1);
+/// # drop(file);
+/// # std::fs::remove_file("subscription.json").unwrap();
+/// # std::fs::remove_file("subscription.json.lock").unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    changes: Arc<Changes>,
+    /// The version of the state last seen.
+    seen: u64,
+}
+
+impl Subscription {
+    /// Wait until the state changes after it was last seen by this
+    /// subscription, which starts when it's created.
+    ///
+    /// Returns `false` once the file has been dropped.
+    pub async fn changed(&mut self) -> bool {
+        loop {
+            if self.mark_seen() {
+                return true;
+            }
+            if self.changes.closed.load(Ordering::SeqCst) {
+                return false;
+            }
+
+            let listener = self.changes.event.listen();
+            // a change between checking and listening would be missed
+            if self.mark_seen() {
+                return true;
+            }
+            if self.changes.closed.load(Ordering::SeqCst) {
+                return false;
+            }
+            listener.await;
+        }
+    }
+
+    /// Whether the state has changed since it was last seen, without marking
+    /// it as seen.
+    pub fn has_changed(&self) -> bool {
+        !self.changes.closed.load(Ordering::SeqCst)
+            && self.changes.version.load(Ordering::SeqCst) != self.seen
+    }
+
+    /// Mark the current version as seen, returning whether it's new.
+    fn mark_seen(&mut self) -> bool {
+        let version = self.changes.version.load(Ordering::SeqCst);
+        let new = version != self.seen;
+        self.seen = version;
+        new
+    }
+}
@@ -0,0 +1,102 @@
+use crate::codec::Codec;
+use crate::format::Format;
+use crate::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+/// What happens to the changes made through a write guard that is dropped
+/// while its thread panics, set with
+/// [`FileBuilder::on_panic`](crate::FileBuilder::on_panic).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnPanic {
+    /// Restore the state as it was when the guard was taken and don't write
+    /// anything. The default.
+    ///
+    /// The state is serialized when the guard is first mutably dereferenced,
+    /// so it can be restored. Fields skipped by serde are reset to their
+    /// defaults.
+    #[default]
+    Rollback,
+    /// Keep the changes in memory but don't write them. They are written by
+    /// the next guard that modifies the state.
+    Skip,
+    /// Write the changes as if the guard had been dropped normally.
+    Write,
+}
+
+/// The state before a change, serialized so it can be restored without
+/// requiring `T: Clone`.
+///
+/// Fields skipped by serde can't be restored and are reset to their defaults.
+pub(crate) struct Snapshot(Vec<u8>);
+
+impl Snapshot {
+    pub(crate) fn take<T: Serialize, F: Format>(
+        codec: &Codec<F>,
+        state: &T,
+    ) -> Result<Self, Error> {
+        codec
+            .format
+            .serialize(state)
+            .map(Snapshot)
+            .map_err(Error::Serialize)
+    }
+
+    /// Replace `state` with the snapshot.
+    pub(crate) fn restore<T: DeserializeOwned, F: Format>(
+        self,
+        codec: &Codec<F>,
+        path: &Path,
+        state: &mut T,
+    ) {
+        match codec.format.deserialize(&self.0) {
+            Ok(snapshot) => *state = snapshot,
+            // not expected, the state was serialized by the same format
+            Err(e) => log::error!("Failed to roll back state: {}", Error::deserialize(path, e)),
+        }
+    }
+}
+
+/// Snapshot the state before a guard changes it, logging any error since a
+/// guard can't report one until it's dropped.
+pub(crate) fn take_snapshot<T: Serialize, F: Format>(
+    codec: &Codec<F>,
+    state: &T,
+) -> Option<Snapshot> {
+    match Snapshot::take(codec, state) {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => {
+            log::warn!("Failed to snapshot state, it can't be rolled back: {}", e);
+            None
+        }
+    }
+}
+
+/// Call `f` with `state`, restoring the state as it was before if `f` returns
+/// an error or panics. Panics are resumed once the state is restored.
+pub(crate) fn run<T, F, R, E>(
+    codec: &Codec<F>,
+    path: &Path,
+    state: &mut T,
+    f: impl FnOnce(&mut T) -> Result<R, E>,
+) -> Result<R, E>
+where
+    T: Serialize + DeserializeOwned,
+    F: Format,
+    E: From<Error>,
+{
+    let snapshot = Snapshot::take(codec, state)?;
+    match panic::catch_unwind(AssertUnwindSafe(|| f(state))) {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => {
+            snapshot.restore(codec, path, state);
+            Err(e)
+        }
+        Err(payload) => {
+            snapshot.restore(codec, path, state);
+            panic::resume_unwind(payload)
+        }
+    }
+}
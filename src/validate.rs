@@ -0,0 +1,47 @@
+use crate::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A check that a state makes sense, beyond being deserializable, enabled with
+/// [`FileBuilder::validated`](crate::FileBuilder::validated).
+///
+/// ```rust
+/// use statefile::Validate;
+///
+/// #[derive(Default)]
+/// struct State {
+///     port: u16,
+/// }
+///
+/// impl Validate for State {
+///     fn validate(&self) -> Result<(), String> {
+///         match self.port {
+///             0 => Err("port must not be 0".to_string()),
+///             _ => Ok(()),
+///         }
+///     }
+/// }
+/// ```
+pub trait Validate {
+    /// Return why the state is invalid, if it is.
+    fn validate(&self) -> Result<(), String>;
+}
+
+/// Checks that a state is valid, returning why it isn't.
+pub(crate) type Validator<T> = Arc<dyn Fn(&T) -> Result<(), String> + Send + Sync>;
+
+/// Check `state` with `validator`, if there is one, on behalf of the file at
+/// `path`.
+pub(crate) fn check<T>(
+    validator: Option<&Validator<T>>,
+    path: &Path,
+    state: &T,
+) -> Result<(), Error> {
+    match validator {
+        Some(validator) => validator(state).map_err(|reason| Error::Invalid {
+            path: path.to_path_buf(),
+            reason,
+        }),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,158 @@
+//! Reloading the state when the file is changed by another process.
+
+use crate::codec::Codec;
+use crate::format::Format;
+use crate::validate::{self, Validator};
+use crate::writer::Reloader;
+use crate::{disk, Error};
+use notify::{EventKind, RecursiveMode, Watcher as _};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The state of a file being watched, protected by the lock of the `File` type
+/// it belongs to.
+pub(crate) enum Shared<T> {
+    #[cfg(feature = "async")]
+    Async(Arc<async_lock::RwLock<T>>),
+    Blocking(Arc<std::sync::RwLock<T>>),
+}
+
+impl<T: Send + Sync + 'static> Shared<T> {
+    fn into_lock(self) -> Arc<dyn StateLock<T>> {
+        match self {
+            #[cfg(feature = "async")]
+            Shared::Async(data) => data,
+            Shared::Blocking(data) => data,
+        }
+    }
+}
+
+/// The lock protecting a state that can be reloaded.
+trait StateLock<T>: Send + Sync {
+    /// Call `f` with exclusive access to the state, blocking until it's
+    /// available.
+    fn with_write(&self, f: &mut dyn FnMut(&mut T));
+}
+
+#[cfg(feature = "async")]
+impl<T: Send + Sync> StateLock<T> for async_lock::RwLock<T> {
+    fn with_write(&self, f: &mut dyn FnMut(&mut T)) {
+        f(&mut self.write_blocking())
+    }
+}
+
+impl<T: Send + Sync> StateLock<T> for std::sync::RwLock<T> {
+    fn with_write(&self, f: &mut dyn FnMut(&mut T)) {
+        f(&mut self.write().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// Watches a state file, replacing the state when the file is changed by
+/// another process. Stops watching when dropped.
+pub(crate) struct Watcher {
+    _watcher: notify::RecommendedWatcher,
+    /// The most recent error from a failed reload.
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+impl Watcher {
+    /// Start watching the file at `path`, replacing `data` with its contents
+    /// if they pass `validator` and then calling `changed`.
+    pub(crate) fn spawn<T, F>(
+        path: PathBuf,
+        data: Shared<T>,
+        codec: Arc<Codec<F>>,
+        validator: Option<Validator<T>>,
+        reloader: Reloader,
+        changed: Box<dyn Fn() + Send>,
+    ) -> Result<Self, Error>
+    where
+        T: Serialize + DeserializeOwned + Default + Send + Sync + 'static,
+        F: Format + Send + Sync + 'static,
+    {
+        let data = data.into_lock();
+        let error = Arc::new(Mutex::new(None));
+
+        let handler = {
+            let path = path.clone();
+            let error = error.clone();
+            move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        log::warn!("Failed to watch {}: {}", path.display(), e);
+                        return;
+                    }
+                };
+                // the directory is watched so replacing the file is noticed
+                let ours = event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == path.file_name());
+                if !ours || matches!(event.kind, EventKind::Access(_) | EventKind::Remove(_)) {
+                    return;
+                }
+
+                data.with_write(&mut |state| {
+                    reloader.reload(|written| {
+                        let result = match std::fs::read(&path) {
+                            Ok(contents) if contents.is_empty() => return None,
+                            Ok(contents) => codec.decode(&path, &contents).and_then(|decoded| {
+                                validate::check(validator.as_ref(), &path, &decoded.state)?;
+                                Ok(decoded)
+                            }),
+                            // it's being replaced, wait for the new file
+                            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+                            Err(e) => Err(Error::io(&path, "read", e)),
+                        };
+
+                        match result {
+                            // written by us
+                            Ok(decoded) if decoded.fingerprint == written => None,
+                            Ok(decoded) => {
+                                log::info!("Reloaded state from {}", path.display());
+                                *state = decoded.state;
+                                changed();
+                                decoded.fingerprint
+                            }
+                            Err(e) => {
+                                // keep the state in memory, which may be written back
+                                log::error!("Failed to reload state: {}", e);
+                                *error.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+                                None
+                            }
+                        }
+                    });
+                });
+            }
+        };
+
+        let mut watcher =
+            notify::recommended_watcher(handler).map_err(|e| watch_error(&path, e))?;
+        let parent = disk::parent(&path);
+        watcher
+            .watch(parent, RecursiveMode::NonRecursive)
+            .map_err(|e| watch_error(parent, e))?;
+
+        Ok(Watcher {
+            _watcher: watcher,
+            error,
+        })
+    }
+
+    /// Take the error from the most recent failed reload.
+    pub(crate) fn take_error(&self) -> Option<Error> {
+        self.error.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+fn watch_error(path: &Path, error: notify::Error) -> Error {
+    let source = match error.kind {
+        notify::ErrorKind::Io(e) => e,
+        _ => io::Error::other(error),
+    };
+    Error::io(path, "watch", source)
+}
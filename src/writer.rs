@@ -0,0 +1,392 @@
+use crate::codec::Encoded;
+use crate::disk::{self, Fsync};
+use crate::{Backups, Error, Permissions};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where to send the result of a write once it has completed.
+pub(crate) enum Reply {
+    Blocking(mpsc::SyncSender<Result<(), Error>>),
+    #[cfg(feature = "async")]
+    Async(async_channel::Sender<Result<(), Error>>),
+}
+
+impl Reply {
+    fn send(self, result: Result<(), Error>) {
+        // the receiver may have gone away, which is fine
+        match self {
+            Reply::Blocking(tx) => {
+                let _ = tx.send(result);
+            }
+            #[cfg(feature = "async")]
+            Reply::Async(tx) => {
+                let _ = tx.try_send(result);
+            }
+        }
+    }
+}
+
+/// A state submitted to be written.
+struct Save {
+    encoded: Encoded,
+    /// The number of reloads when the state was submitted.
+    reloads: u64,
+}
+
+enum Command {
+    /// Write to disk, replying with the result if requested.
+    Save(Save, Option<Reply>),
+    /// Reply once every previous save has been written.
+    Flush(Reply),
+    /// Change the minimum interval between writes of dropped guards.
+    Debounce(Option<Duration>),
+    /// Report an error from a save that was rejected before being submitted.
+    Report(Error),
+}
+
+/// Counters describing the writes made to a state file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of times the file was written to disk.
+    pub writes: u64,
+    /// Number of writes skipped because the state hadn't changed.
+    pub skipped: u64,
+    /// Number of writes replaced by a newer one while debouncing.
+    pub coalesced: u64,
+    /// Number of times the state was reloaded after the file was changed by
+    /// another process.
+    pub reloads: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    writes: AtomicU64,
+    skipped: AtomicU64,
+    coalesced: AtomicU64,
+    reloads: AtomicU64,
+}
+
+/// Handle to the background thread that writes a state file to disk.
+///
+/// Writes are performed in the order they were submitted. Dropping the handle
+/// waits for any pending writes to complete.
+///
+/// With debouncing enabled, saves that nobody waits on are coalesced so that at
+/// most one of them is written per interval. Only the most recent is kept.
+///
+/// Saves with the same fingerprint as the last successful write are skipped, as
+/// are saves submitted before the state was reloaded from disk.
+pub(crate) struct Writer {
+    path: PathBuf,
+    tx: Option<mpsc::Sender<Command>>,
+    thread: Option<thread::JoinHandle<()>>,
+    counters: Arc<Counters>,
+    /// Fingerprint of the state on disk, if known.
+    #[cfg(feature = "watch")]
+    written: Arc<Mutex<Option<u64>>>,
+}
+
+impl Writer {
+    /// Start a writer for `path`, whose current contents have the given
+    /// fingerprint.
+    ///
+    /// A read-only writer fails every write that isn't skipped.
+    pub(crate) fn spawn(
+        path: PathBuf,
+        fingerprint: Option<u64>,
+        read_only: bool,
+        backups: Option<Backups>,
+        permissions: Permissions,
+        fsync: Fsync,
+    ) -> Result<Self, Error> {
+        let (tx, rx) = mpsc::channel();
+        let counters = Arc::new(Counters::default());
+        let written = Arc::new(Mutex::new(fingerprint));
+
+        let worker = Worker {
+            path: path.clone(),
+            last_write: None,
+            written: written.clone(),
+            counters: counters.clone(),
+            read_only,
+            backups,
+            permissions,
+            fsync,
+        };
+        let thread = thread::Builder::new()
+            .name("statefile-writer".to_string())
+            .spawn(move || worker.run(rx))
+            .map_err(|e| Error::io(&path, "spawn writer for", e))?;
+
+        Ok(Writer {
+            path,
+            tx: Some(tx),
+            thread: Some(thread),
+            counters,
+            #[cfg(feature = "watch")]
+            written,
+        })
+    }
+
+    /// Record a save that was skipped without being submitted.
+    pub(crate) fn skip(&self) {
+        self.counters.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        Stats {
+            writes: self.counters.writes.load(Ordering::Relaxed),
+            skipped: self.counters.skipped.load(Ordering::Relaxed),
+            coalesced: self.counters.coalesced.load(Ordering::Relaxed),
+            reloads: self.counters.reloads.load(Ordering::Relaxed),
+        }
+    }
+
+    /// A handle for replacing the state with the contents of the file.
+    #[cfg(feature = "watch")]
+    pub(crate) fn reloader(&self) -> Reloader {
+        Reloader {
+            counters: self.counters.clone(),
+            written: self.written.clone(),
+        }
+    }
+
+    /// Queue the state to be written without waiting for the result.
+    pub(crate) fn save(&self, encoded: Encoded) {
+        self.send(Command::Save(self.submit(encoded), None));
+    }
+
+    /// Write the state, blocking the current thread until it's on disk.
+    pub(crate) fn save_blocking(&self, encoded: Encoded) -> Result<(), Error> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.send(Command::Save(
+            self.submit(encoded),
+            Some(Reply::Blocking(tx)),
+        ));
+        rx.recv().unwrap_or_else(|_| Err(self.stopped()))
+    }
+
+    /// Write the state, waiting until it's on disk.
+    #[cfg(feature = "async")]
+    pub(crate) async fn save_async(&self, encoded: Encoded) -> Result<(), Error> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.send(Command::Save(self.submit(encoded), Some(Reply::Async(tx))));
+        rx.recv().await.unwrap_or_else(|_| Err(self.stopped()))
+    }
+
+    /// Wait for all queued writes to complete, returning the first error from a
+    /// write nobody was waiting on since the last flush.
+    #[cfg(feature = "async")]
+    pub(crate) async fn flush(&self) -> Result<(), Error> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.send(Command::Flush(Reply::Async(tx)));
+        rx.recv().await.unwrap_or_else(|_| Err(self.stopped()))
+    }
+
+    /// Like [`Writer::flush`], blocking the current thread.
+    pub(crate) fn flush_blocking(&self) -> Result<(), Error> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.send(Command::Flush(Reply::Blocking(tx)));
+        rx.recv().unwrap_or_else(|_| Err(self.stopped()))
+    }
+
+    /// Report `error` from the next flush, as if a write had failed.
+    pub(crate) fn report(&self, error: Error) {
+        self.send(Command::Report(error));
+    }
+
+    /// Set the minimum interval between writes of dropped guards, or `None` to
+    /// write each of them immediately.
+    pub(crate) fn debounce(&self, interval: Option<Duration>) {
+        self.send(Command::Debounce(interval));
+    }
+
+    fn submit(&self, encoded: Encoded) -> Save {
+        Save {
+            encoded,
+            // saves are submitted under the state's lock, as are reloads
+            reloads: self.counters.reloads.load(Ordering::Acquire),
+        }
+    }
+
+    fn send(&self, command: Command) {
+        if let Some(tx) = &self.tx {
+            // if the thread has died the reply channel reports it
+            let _ = tx.send(command);
+        }
+    }
+
+    fn stopped(&self) -> Error {
+        Error::io(
+            &self.path,
+            "write",
+            io::Error::other("writer thread has stopped"),
+        )
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // closing the channel lets the thread finish the remaining writes
+        drop(self.tx.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Replaces the state with the contents of the file without racing the writer.
+#[cfg(feature = "watch")]
+pub(crate) struct Reloader {
+    counters: Arc<Counters>,
+    written: Arc<Mutex<Option<u64>>>,
+}
+
+#[cfg(feature = "watch")]
+impl Reloader {
+    /// Call `reload` with the fingerprint of the state on disk, preventing
+    /// writes until it returns.
+    ///
+    /// `reload` returns the fingerprint of the state it loaded, if it did, in
+    /// which case any saves submitted before then are discarded. It must be
+    /// called while holding the state's write lock.
+    pub(crate) fn reload(&self, reload: impl FnOnce(Option<u64>) -> Option<u64>) {
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(fingerprint) = reload(*written) {
+            *written = Some(fingerprint);
+            self.counters.reloads.fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+/// State owned by the writer thread.
+struct Worker {
+    path: PathBuf,
+    last_write: Option<Instant>,
+    /// Fingerprint of the state on disk, if known.
+    written: Arc<Mutex<Option<u64>>>,
+    counters: Arc<Counters>,
+    read_only: bool,
+    backups: Option<Backups>,
+    permissions: Permissions,
+    fsync: Fsync,
+}
+
+impl Worker {
+    fn run(mut self, rx: mpsc::Receiver<Command>) {
+        let mut interval: Option<Duration> = None;
+        // most recent save waiting for the interval to elapse
+        let mut pending: Option<Save> = None;
+        // error from a write that had nobody to report to
+        let mut unreported: Option<Error> = None;
+
+        loop {
+            let deadline = match (&pending, interval, self.last_write) {
+                (Some(_), Some(interval), Some(last_write)) => Some(last_write + interval),
+                (Some(_), _, _) => Some(Instant::now()),
+                (None, _, _) => None,
+            };
+
+            let command = match deadline {
+                Some(deadline) => {
+                    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(command) => Some(command),
+                        Err(mpsc::RecvTimeoutError::Timeout) => None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match rx.recv() {
+                    Ok(command) => Some(command),
+                    Err(mpsc::RecvError) => break,
+                },
+            };
+
+            match command {
+                // a waiting save is due
+                None => {
+                    if let Some(save) = pending.take() {
+                        if let Err(e) = self.write(save) {
+                            unreported.get_or_insert(e);
+                        }
+                    }
+                }
+                // queue for the next interval, replacing anything older
+                Some(Command::Save(save, None)) => {
+                    if pending.replace(save).is_some() {
+                        self.counters.coalesced.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                // somebody is waiting, so write immediately
+                Some(Command::Save(save, Some(reply))) => {
+                    if pending.take().is_some() {
+                        self.counters.coalesced.fetch_add(1, Ordering::Relaxed);
+                    }
+                    reply.send(self.write(save));
+                }
+                Some(Command::Flush(reply)) => {
+                    if let Some(save) = pending.take() {
+                        if let Err(e) = self.write(save) {
+                            unreported.get_or_insert(e);
+                        }
+                    }
+                    reply.send(unreported.take().map_or(Ok(()), Err));
+                }
+                Some(Command::Debounce(new_interval)) => interval = new_interval,
+                Some(Command::Report(e)) => {
+                    unreported.get_or_insert(e);
+                }
+            }
+        }
+
+        // the file is being closed, so write whatever is left
+        if let Some(save) = pending.take() {
+            let _ = self.write(save);
+        }
+    }
+
+    fn write(&mut self, save: Save) -> Result<(), Error> {
+        // held until the file matches the fingerprint, so reloads can tell our
+        // own writes from changes made by other processes
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+
+        let encoded = save.encoded;
+        let reloaded = save.reloads != self.counters.reloads.load(Ordering::Acquire);
+        if reloaded || (!encoded.force && *written == Some(encoded.fingerprint)) {
+            self.counters.skipped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        if self.read_only {
+            return Err(Error::ReadOnly {
+                path: self.path.clone(),
+            });
+        }
+
+        if let Some(backups) = &self.backups {
+            // losing a backup is better than losing the new state
+            if let Err(e) = backups.rotate(&self.path) {
+                log::warn!("Failed to back up state: {}", e);
+            }
+        }
+
+        self.last_write = Some(Instant::now());
+        let result = disk::write_atomic(&self.path, &encoded.bytes, &self.permissions, self.fsync);
+        match &result {
+            Ok(()) => {
+                *written = Some(encoded.fingerprint);
+                self.counters.writes.fetch_add(1, Ordering::Relaxed);
+                log::info!("Data successfully written to file {}", self.path.display());
+            }
+            Err(e) => {
+                // the file may or may not have been replaced
+                *written = None;
+                log::error!("Failed to write state: {}", e);
+            }
+        }
+        result
+    }
+}